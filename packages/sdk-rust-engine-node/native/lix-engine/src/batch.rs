@@ -0,0 +1,217 @@
+use serde::Serialize;
+use serde_json::Value;
+
+use lix_engine::{plan_execute, route_statement_kind, ExecutePlan};
+
+#[derive(Debug, Serialize)]
+pub struct BatchStatement {
+    pub sql: String,
+    pub statement_kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub plan: Option<ExecutePlan>,
+}
+
+/// Splits a whole SQL script into top-level statements, routes each one, and
+/// optionally attaches its `plan_execute` output.
+pub fn route_batch(script: &str, with_plan: bool) -> Vec<BatchStatement> {
+    split_statements(script)
+        .into_iter()
+        .map(|sql| {
+            let statement_kind = route_statement_kind(&sql);
+            let plan = if with_plan {
+                Some(plan_execute(&sql))
+            } else {
+                None
+            };
+            BatchStatement {
+                sql,
+                statement_kind,
+                plan,
+            }
+        })
+        .collect()
+}
+
+pub fn batch_to_json(statements: &[BatchStatement]) -> Value {
+    serde_json::to_value(statements).expect("BatchStatement always serializes")
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum SplitterState {
+    Default,
+    SingleQuoted,
+    DoubleQuoted,
+    DollarQuoted,
+    LineComment,
+    BlockComment,
+}
+
+/// Splits `script` on top-level semicolons, respecting single/double-quoted
+/// strings, `$tag$...$tag$` dollar-quoted bodies, and `--`/`/* */` comments so
+/// semicolons inside any of those never cause a false split.
+pub fn split_statements(script: &str) -> Vec<String> {
+    let mut statements = Vec::new();
+    let mut current = String::new();
+    let mut state = SplitterState::Default;
+    let mut dollar_tag = String::new();
+
+    let chars: Vec<char> = script.chars().collect();
+    let mut index = 0;
+
+    while index < chars.len() {
+        let ch = chars[index];
+
+        match state {
+            SplitterState::Default => {
+                if ch == '\'' {
+                    state = SplitterState::SingleQuoted;
+                    current.push(ch);
+                } else if ch == '"' {
+                    state = SplitterState::DoubleQuoted;
+                    current.push(ch);
+                } else if ch == '-' && chars.get(index + 1) == Some(&'-') {
+                    state = SplitterState::LineComment;
+                    current.push(ch);
+                    current.push('-');
+                    index += 1;
+                } else if ch == '/' && chars.get(index + 1) == Some(&'*') {
+                    state = SplitterState::BlockComment;
+                    current.push(ch);
+                    current.push('*');
+                    index += 1;
+                } else if ch == '$' {
+                    if let Some((tag, consumed)) = read_dollar_tag(&chars, index) {
+                        dollar_tag = tag;
+                        current.push_str(&chars[index..index + consumed].iter().collect::<String>());
+                        index += consumed - 1;
+                        state = SplitterState::DollarQuoted;
+                    } else {
+                        current.push(ch);
+                    }
+                } else if ch == ';' {
+                    let trimmed = current.trim();
+                    if !trimmed.is_empty() {
+                        statements.push(trimmed.to_owned());
+                    }
+                    current.clear();
+                } else {
+                    current.push(ch);
+                }
+            }
+            SplitterState::SingleQuoted => {
+                current.push(ch);
+                if ch == '\'' {
+                    state = SplitterState::Default;
+                }
+            }
+            SplitterState::DoubleQuoted => {
+                current.push(ch);
+                if ch == '"' {
+                    state = SplitterState::Default;
+                }
+            }
+            SplitterState::LineComment => {
+                current.push(ch);
+                if ch == '\n' {
+                    state = SplitterState::Default;
+                }
+            }
+            SplitterState::BlockComment => {
+                current.push(ch);
+                if ch == '*' && chars.get(index + 1) == Some(&'/') {
+                    current.push('/');
+                    index += 1;
+                    state = SplitterState::Default;
+                }
+            }
+            SplitterState::DollarQuoted => {
+                let closing = format!("${dollar_tag}$");
+                if script_matches_at(&chars, index, &closing) {
+                    current.push_str(&closing);
+                    index += closing.chars().count() - 1;
+                    state = SplitterState::Default;
+                } else {
+                    current.push(ch);
+                }
+            }
+        }
+
+        index += 1;
+    }
+
+    let trimmed = current.trim();
+    if !trimmed.is_empty() {
+        statements.push(trimmed.to_owned());
+    }
+
+    statements
+}
+
+fn read_dollar_tag(chars: &[char], start: usize) -> Option<(String, usize)> {
+    let mut index = start + 1;
+    let mut tag = String::new();
+    while let Some(&ch) = chars.get(index) {
+        if ch == '$' {
+            return Some((tag, index - start + 1));
+        }
+        if !(ch.is_alphanumeric() || ch == '_') {
+            return None;
+        }
+        tag.push(ch);
+        index += 1;
+    }
+    None
+}
+
+fn script_matches_at(chars: &[char], index: usize, needle: &str) -> bool {
+    let needle_chars: Vec<char> = needle.chars().collect();
+    if index + needle_chars.len() > chars.len() {
+        return false;
+    }
+    chars[index..index + needle_chars.len()] == needle_chars[..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_statements;
+
+    #[test]
+    fn splits_on_top_level_semicolons() {
+        let script = "select 1; select 2;";
+        assert_eq!(
+            split_statements(script),
+            vec!["select 1".to_owned(), "select 2".to_owned()]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_inside_string_literals() {
+        let script = "select 'a;b'; select 2;";
+        assert_eq!(
+            split_statements(script),
+            vec!["select 'a;b'".to_owned(), "select 2".to_owned()]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_inside_line_and_block_comments() {
+        let script = "select 1; -- a;b\nselect 2; /* c;d */ select 3;";
+        assert_eq!(
+            split_statements(script),
+            vec![
+                "select 1".to_owned(),
+                "-- a;b\nselect 2".to_owned(),
+                "/* c;d */ select 3".to_owned(),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_semicolons_inside_dollar_quoted_bodies() {
+        let script = "select $tag$a;b$tag$; select 2;";
+        assert_eq!(
+            split_statements(script),
+            vec!["select $tag$a;b$tag$".to_owned(), "select 2".to_owned()]
+        );
+    }
+}
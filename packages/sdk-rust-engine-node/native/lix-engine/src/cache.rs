@@ -0,0 +1,122 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use lix_engine::ExecutePlan;
+
+const CACHE_DIR: &str = ".lix";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CacheEntry {
+    pub routed_kind: String,
+    pub plan: ExecutePlan,
+}
+
+/// Normalizes SQL the way the offline cache keys on it: trims surrounding
+/// whitespace, collapses runs of whitespace, and lowercases everything
+/// outside of single/double-quoted string and identifier literals so that
+/// cosmetic differences don't produce distinct cache entries.
+pub fn normalize_sql(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut chars = sql.trim().chars().peekable();
+    let mut last_was_space = false;
+    let mut quote: Option<char> = None;
+
+    while let Some(ch) = chars.next() {
+        if let Some(open) = quote {
+            normalized.push(ch);
+            if ch == open {
+                quote = None;
+            }
+            last_was_space = false;
+            continue;
+        }
+
+        if ch == '\'' || ch == '"' {
+            quote = Some(ch);
+            normalized.push(ch);
+            last_was_space = false;
+            continue;
+        }
+
+        if ch.is_whitespace() {
+            if !last_was_space {
+                normalized.push(' ');
+                last_was_space = true;
+            }
+            continue;
+        }
+
+        normalized.extend(ch.to_lowercase());
+        last_was_space = false;
+    }
+
+    normalized
+}
+
+/// Stable cache key for a given command (`route`/`plan`/`rewrite`) applied to
+/// already-normalized SQL.
+pub fn cache_key(normalized_sql: &str, command: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(normalized_sql.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(command.as_bytes());
+    let digest = hasher.finalize();
+    digest.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn cache_dir() -> PathBuf {
+    PathBuf::from(CACHE_DIR)
+}
+
+fn cache_path(hash: &str) -> PathBuf {
+    cache_dir().join(format!("{hash}.json"))
+}
+
+pub fn load(hash: &str) -> Option<CacheEntry> {
+    let path = cache_path(hash);
+    let contents = fs::read_to_string(path).ok()?;
+    serde_json::from_str(&contents).ok()
+}
+
+/// Writes the entry atomically: write to a sibling temp file, then rename
+/// over the destination so a crash mid-write never leaves a truncated
+/// `.lix/<hash>.json`.
+pub fn store(hash: &str, entry: &CacheEntry) -> io::Result<()> {
+    let dir = cache_dir();
+    fs::create_dir_all(&dir)?;
+
+    let path = cache_path(hash);
+    let tmp_path = dir.join(format!("{hash}.json.tmp"));
+    let body = serde_json::to_vec(entry).expect("CacheEntry always serializes");
+    fs::write(&tmp_path, body)?;
+    fs::rename(&tmp_path, &path)?;
+    Ok(())
+}
+
+/// Pre-populates the cache for every statement in `source`, splitting naively
+/// on statement-terminating semicolons. Used by the `prepare` subcommand to
+/// warm `.lix/` as a build-time precompute step.
+pub fn prepare_statements(source: &str) -> Vec<String> {
+    source
+        .split(';')
+        .map(str::trim)
+        .filter(|statement| !statement.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+pub fn read_source(path: Option<&Path>) -> io::Result<String> {
+    match path {
+        Some(path) => fs::read_to_string(path),
+        None => {
+            use io::Read;
+            let mut buffer = String::new();
+            io::stdin().read_to_string(&mut buffer)?;
+            Ok(buffer)
+        }
+    }
+}
@@ -0,0 +1,305 @@
+use sqlparser::ast::{Query, Select, SelectItem, SetExpr, Statement, TableWithJoins};
+use sqlparser::dialect::SQLiteDialect;
+use sqlparser::parser::Parser;
+
+use lix_engine::EngineError;
+
+const INDENT: &str = "    ";
+
+/// Parses `sql` and re-emits it in a normalized, pretty-printed form:
+/// `SELECT`/`FROM`/`WHERE`/`JOIN` each on their own line with indented column
+/// lists, keywords uppercased, and string/identifier literal contents left
+/// untouched. Trailing `--` line comments are carried over in source order
+/// and reattached to the clause they followed (a comment before the first
+/// keyword is treated as leading the whole statement).
+pub fn format_sql(sql: &str) -> Result<String, EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql)
+        .map_err(|error| EngineError::new("LIX_RUST_PROTOCOL_MISMATCH", format!("failed to parse SQL for format: {error}")))?;
+
+    if statements.is_empty() {
+        return Err(EngineError::new(
+            "LIX_RUST_PROTOCOL_MISMATCH",
+            "expected at least one statement to format",
+        ));
+    }
+
+    let statement_sources = split_into_statement_sources(sql);
+
+    let mut formatted_statements: Vec<String> = Vec::with_capacity(statements.len());
+    for (index, statement) in statements.iter().enumerate() {
+        let comments = statement_sources
+            .get(index)
+            .map(|source| extract_clause_comments(source))
+            .unwrap_or_default();
+        formatted_statements.push(format_statement(statement, &comments));
+    }
+
+    Ok(formatted_statements.join(";\n"))
+}
+
+/// The clause a trailing `--` comment followed, used to reattach it to the
+/// matching line of the formatted output instead of the end of the output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Clause {
+    Leading,
+    Select,
+    From,
+    Where,
+}
+
+fn format_statement(statement: &Statement, comments: &[(Clause, String)]) -> String {
+    let mut rendered = match statement {
+        Statement::Query(query) => format_query(query, 0, comments),
+        other => uppercase_keywords(&other.to_string()),
+    };
+
+    if !matches!(statement, Statement::Query(query) if matches!(&*query.body, SetExpr::Select(_))) {
+        for (clause, comment) in comments {
+            if *clause != Clause::Leading {
+                rendered.push_str("\n-- ");
+                rendered.push_str(comment);
+            }
+        }
+    }
+
+    let leading: Vec<&str> = comments
+        .iter()
+        .filter(|(clause, _)| *clause == Clause::Leading)
+        .map(|(_, comment)| comment.as_str())
+        .collect();
+    if leading.is_empty() {
+        rendered
+    } else {
+        let mut prefixed = leading
+            .iter()
+            .map(|comment| format!("-- {comment}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        prefixed.push('\n');
+        prefixed.push_str(&rendered);
+        prefixed
+    }
+}
+
+fn format_query(query: &Query, depth: usize, comments: &[(Clause, String)]) -> String {
+    let indent = INDENT.repeat(depth);
+    match &*query.body {
+        SetExpr::Select(select) => format_select(select, depth, comments),
+        _ => format!("{indent}{}", uppercase_keywords(&query.to_string())),
+    }
+}
+
+fn format_select(select: &Select, depth: usize, comments: &[(Clause, String)]) -> String {
+    let indent = INDENT.repeat(depth);
+    let column_indent = INDENT.repeat(depth + 1);
+    let mut lines: Vec<String> = Vec::new();
+
+    lines.push(format!("{indent}SELECT"));
+    let projection: Vec<String> = select
+        .projection
+        .iter()
+        .map(|item| format_select_item(item))
+        .collect();
+    let mut projection_line = format!("{column_indent}{}", projection.join(",\n    "));
+    append_clause_comments(&mut projection_line, comments, Clause::Select);
+    lines.push(projection_line);
+
+    if let Some(first_table) = select.from.first() {
+        lines.push(format!("{indent}FROM"));
+        lines.push(format!("{column_indent}{}", format_table_with_joins(first_table)));
+        for table in select.from.iter().skip(1) {
+            lines.push(format!("{column_indent}, {}", format_table_with_joins(table)));
+        }
+        if let Some(from_line) = lines.last_mut() {
+            append_clause_comments(from_line, comments, Clause::From);
+        }
+    }
+
+    if let Some(selection) = &select.selection {
+        lines.push(format!("{indent}WHERE"));
+        let mut where_line = format!("{column_indent}{}", selection);
+        append_clause_comments(&mut where_line, comments, Clause::Where);
+        lines.push(where_line);
+    }
+
+    lines.join("\n")
+}
+
+/// Appends any comments tagged with `clause` to the end of `line`, in
+/// source order.
+fn append_clause_comments(line: &mut String, comments: &[(Clause, String)], clause: Clause) {
+    for (tagged_clause, comment) in comments {
+        if *tagged_clause == clause {
+            line.push_str("  -- ");
+            line.push_str(comment);
+        }
+    }
+}
+
+fn format_select_item(item: &SelectItem) -> String {
+    item.to_string()
+}
+
+fn format_table_with_joins(table_with_joins: &TableWithJoins) -> String {
+    let mut rendered = table_with_joins.relation.to_string();
+    for join in &table_with_joins.joins {
+        rendered.push_str(&format!("\n{}", join.to_string().trim_start()));
+    }
+    rendered
+}
+
+/// Uppercases SQL keywords in an already-rendered statement string while
+/// leaving quoted string/identifier contents untouched.
+fn uppercase_keywords(rendered: &str) -> String {
+    const KEYWORDS: &[&str] = &[
+        "select", "from", "where", "join", "inner", "left", "right", "outer", "on", "and", "or",
+        "insert", "into", "values", "update", "set", "delete", "order", "by", "group", "limit",
+        "offset", "as", "distinct", "having", "not", "null", "is", "in", "like", "between",
+    ];
+
+    let mut result = String::with_capacity(rendered.len());
+    let mut quote: Option<char> = None;
+    let mut word = String::new();
+
+    let flush_word = |word: &mut String, result: &mut String| {
+        if word.is_empty() {
+            return;
+        }
+        if KEYWORDS.contains(&word.to_lowercase().as_str()) {
+            result.push_str(&word.to_uppercase());
+        } else {
+            result.push_str(word);
+        }
+        word.clear();
+    };
+
+    for ch in rendered.chars() {
+        if let Some(open) = quote {
+            result.push(ch);
+            if ch == open {
+                quote = None;
+            }
+            continue;
+        }
+
+        if ch == '\'' || ch == '"' {
+            flush_word(&mut word, &mut result);
+            quote = Some(ch);
+            result.push(ch);
+            continue;
+        }
+
+        if ch.is_alphanumeric() || ch == '_' {
+            word.push(ch);
+        } else {
+            flush_word(&mut word, &mut result);
+            result.push(ch);
+        }
+    }
+    flush_word(&mut word, &mut result);
+
+    result
+}
+
+/// Splits `sql` into one raw source slice per top-level statement (on `;`
+/// outside string literals), in the same order `Parser::parse_sql` returns
+/// its statements, so each statement's comments can be extracted from its
+/// own source text rather than the whole input.
+fn split_into_statement_sources(sql: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+
+    for ch in sql.chars() {
+        if let Some(open) = quote {
+            current.push(ch);
+            if ch == open {
+                quote = None;
+            }
+            continue;
+        }
+
+        if ch == '\'' || ch == '"' {
+            quote = Some(ch);
+            current.push(ch);
+            continue;
+        }
+
+        if ch == ';' {
+            parts.push(std::mem::take(&mut current));
+            continue;
+        }
+
+        current.push(ch);
+    }
+    if !current.trim().is_empty() {
+        parts.push(current);
+    }
+
+    parts
+}
+
+/// Collects `-- ...` line comments found outside string literals in a single
+/// statement's source text, tagging each with the clause keyword it
+/// followed (or [`Clause::Leading`] if it precedes the first keyword) so it
+/// can be reattached to that clause instead of the tail of the output.
+fn extract_clause_comments(statement_sql: &str) -> Vec<(Clause, String)> {
+    const CLAUSE_KEYWORDS: &[(&str, Clause)] =
+        &[("select", Clause::Select), ("from", Clause::From), ("where", Clause::Where)];
+
+    let mut comments = Vec::new();
+    let mut current_clause = Clause::Leading;
+    let mut quote: Option<char> = None;
+    let mut word = String::new();
+    let mut chars = statement_sql.chars().peekable();
+
+    let flush_word = |word: &mut String, current_clause: &mut Clause| {
+        if word.is_empty() {
+            return;
+        }
+        let lowered = word.to_lowercase();
+        if let Some((_, clause)) = CLAUSE_KEYWORDS.iter().find(|(keyword, _)| *keyword == lowered) {
+            *current_clause = *clause;
+        }
+        word.clear();
+    };
+
+    while let Some(ch) = chars.next() {
+        if let Some(open) = quote {
+            if ch == open {
+                quote = None;
+            }
+            continue;
+        }
+
+        if ch == '\'' || ch == '"' {
+            flush_word(&mut word, &mut current_clause);
+            quote = Some(ch);
+            continue;
+        }
+
+        if ch == '-' && chars.peek() == Some(&'-') {
+            flush_word(&mut word, &mut current_clause);
+            chars.next();
+            let mut comment = String::new();
+            for next in chars.by_ref() {
+                if next == '\n' {
+                    break;
+                }
+                comment.push(next);
+            }
+            comments.push((current_clause, comment.trim().to_owned()));
+            continue;
+        }
+
+        if ch.is_alphanumeric() || ch == '_' {
+            word.push(ch);
+        } else {
+            flush_word(&mut word, &mut current_clause);
+        }
+    }
+    flush_word(&mut word, &mut current_clause);
+
+    comments
+}
@@ -0,0 +1,50 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use serde::Deserialize;
+
+use lix_engine::{plan_execute, rewrite_sql_for_execution, route_statement_kind};
+
+#[derive(Debug, Deserialize)]
+struct SqlRequest {
+    sql: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RewriteRequest {
+    sql: String,
+    statement_kind: String,
+}
+
+async fn route_handler(body: web::Json<SqlRequest>) -> HttpResponse {
+    let kind = route_statement_kind(&body.sql);
+    HttpResponse::Ok().json(kind)
+}
+
+async fn plan_handler(body: web::Json<SqlRequest>) -> HttpResponse {
+    let plan = plan_execute(&body.sql);
+    HttpResponse::Ok().json(plan)
+}
+
+async fn rewrite_handler(body: web::Json<RewriteRequest>) -> HttpResponse {
+    match rewrite_sql_for_execution(&body.sql, &body.statement_kind) {
+        Ok(rewritten) => HttpResponse::Ok().json(rewritten),
+        Err(error) => HttpResponse::BadRequest().json(error),
+    }
+}
+
+/// Runs an embedded HTTP server exposing `route`/`plan`/`rewrite` as REST
+/// endpoints over the same stateless engine functions the CLI commands use,
+/// so non-Rust callers can reach the engine without shelling out.
+pub fn run_serve_http(addr: &str) -> std::io::Result<()> {
+    let addr = addr.to_owned();
+    actix_web::rt::System::new().block_on(async move {
+        HttpServer::new(|| {
+            App::new()
+                .route("/route", web::post().to(route_handler))
+                .route("/plan", web::post().to(plan_handler))
+                .route("/rewrite", web::post().to(rewrite_handler))
+        })
+        .bind(addr)?
+        .run()
+        .await
+    })
+}
@@ -1,10 +1,12 @@
 use cel_interpreter::Program;
+use jsonschema::error::ValidationErrorKind;
 use jsonschema::JSONSchema;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlparser::ast::{
     Delete, Expr, FromTable, FunctionArg, FunctionArgExpr, FunctionArguments, Ident, Insert,
-    ObjectName, Query, Select, SetExpr, Statement, TableAlias, TableFactor, TableWithJoins,
+    ObjectName, OnConflictAction, OnInsert, Query, Select, SetExpr, Statement, TableAlias,
+    TableFactor, TableWithJoins,
 };
 use sqlparser::dialect::SQLiteDialect;
 use sqlparser::parser::Parser;
@@ -24,14 +26,29 @@ pub const LIX_RUST_UNSUPPORTED_SQLITE_FEATURE: &str = "LIX_RUST_UNSUPPORTED_SQLI
 pub const LIX_RUST_PROTOCOL_MISMATCH: &str = "LIX_RUST_PROTOCOL_MISMATCH";
 pub const LIX_RUST_TIMEOUT: &str = "LIX_RUST_TIMEOUT";
 pub const LIX_RUST_UNKNOWN: &str = "LIX_RUST_UNKNOWN";
+pub const LIX_RUST_QUOTA_EXCEEDED: &str = "LIX_RUST_QUOTA_EXCEEDED";
 const INTERNAL_STATE_VTABLE: &str = "lix_internal_state_vtable";
 const STATE_BY_VERSION: &str = "state_by_version";
 const STATE_VIEW: &str = "state";
+const ACTIVE_VERSION_VIEW: &str = "active_version";
 const STATE_ALL_VIEW: &str = "state_all";
+const FILE_TABLE: &str = "file";
+const LIX_FILE_SCHEMA_KEY: &str = "lix_file";
 const MUTATION_ROW_CTE: &str = "__lix_mutation_rows";
 const STATE_MUTATION_KEY_COLUMNS: [&str; 4] = ["entity_id", "schema_key", "file_id", "version_id"];
 
-#[derive(Debug, Serialize, PartialEq, Eq)]
+/// Columns announced via `SubscriptionEvent::Columns` before replaying a new
+/// subscriber's initial result set, mirroring `STATE_MUTATION_KEY_COLUMNS`
+/// plus the `snapshot_content` every state view row carries.
+const STATE_SUBSCRIPTION_REPLAY_COLUMNS: [&str; 5] = [
+    "entity_id",
+    "schema_key",
+    "file_id",
+    "version_id",
+    "snapshot_content",
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct ExecutePlan {
     pub statement_kind: &'static str,
@@ -48,6 +65,35 @@ pub struct ExecuteRequest {
     pub params: Vec<Value>,
     #[serde(default)]
     pub plugin_change_requests: Vec<PluginChangeRequest>,
+    #[serde(default)]
+    pub as_of: Option<AsOf>,
+    #[serde(default)]
+    pub prepared_name: Option<String>,
+    /// When set for a WRITE_REWRITE or VALIDATION statement, runs
+    /// classification, rewriting, schema validation, and plugin-change
+    /// detection as usual but skips the `host.execute` call that would
+    /// actually mutate state — a preview of what the statement *would* do.
+    /// Has no effect on READ_REWRITE/PASSTHROUGH statements, which never
+    /// mutate state in the first place. Honoured by `execute_with_host` and
+    /// the wrappers built on it (`execute_with_host_and_subscriptions`,
+    /// `execute_with_host_and_observers`); `execute_with_host_cached`,
+    /// `execute_with_host_and_plan_cache`, `execute_with_host_and_prepared_cache`
+    /// and `execute_with_host_and_quotas` ignore it and always call
+    /// `host.execute`.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Selects a historical point to read `lix_internal_state_vtable` as of,
+/// instead of the live untracked view. Exactly one of the two forms is
+/// meaningful at a time: a specific commit (and everything reachable from
+/// it), or an RFC3339 timestamp (the latest row per identity as of that
+/// instant).
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum AsOf {
+    CommitId(String),
+    Timestamp(String),
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -68,6 +114,78 @@ pub struct ExecuteResult {
     pub rows_affected: i64,
     pub last_insert_row_id: Option<i64>,
     pub plugin_changes: Vec<Value>,
+    #[serde(default)]
+    pub upsert_resolutions: Vec<UpsertResolution>,
+    #[serde(default)]
+    pub transaction_report: TransactionReport,
+    /// The rewritten SQL the engine would have sent to `host.execute`,
+    /// populated when `ExecuteRequest::dry_run` skipped that call so the
+    /// caller can inspect or diff what would have run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rewritten_sql: Option<String>,
+}
+
+/// Per-row outcome of resolving an `INSERT ... ON CONFLICT(...) DO UPDATE`
+/// mutation against existing state, keyed by `STATE_MUTATION_KEY_COLUMNS`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpsertResolution {
+    pub entity_id: Option<String>,
+    pub schema_key: String,
+    pub file_id: Option<String>,
+    pub version_id: Option<String>,
+    pub outcome: UpsertOutcome,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum UpsertOutcome {
+    Created,
+    Updated,
+}
+
+/// How a conflicting row's `snapshot_content` is reconciled with the
+/// existing stored row during upsert resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConflictMergeMode {
+    Replace,
+    ShallowMerge,
+    DeepMerge,
+}
+
+/// The semantic effect of a mutation statement on state: everything that was
+/// asserted (inserted or updated) and everything that was retracted
+/// (deleted), reported alongside `ExecuteResult` so downstream subsystems can
+/// react to what actually changed instead of re-parsing the executed SQL.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionReport {
+    #[serde(default)]
+    pub asserted: Vec<TransactionEffect>,
+    #[serde(default)]
+    pub retracted: Vec<TransactionEffect>,
+}
+
+impl TransactionReport {
+    fn is_empty(&self) -> bool {
+        self.asserted.is_empty() && self.retracted.is_empty()
+    }
+}
+
+/// One entity touched by a mutation statement, identified by
+/// `STATE_MUTATION_KEY_COLUMNS`. `schema` is populated for inserts (which go
+/// through `collect_mutation_row_issues` and therefore already have the
+/// stored schema on hand); UPDATE/DELETE effects are derived from the
+/// statement's WHERE-clause equalities alone, so `schema` is `None`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TransactionEffect {
+    pub entity_id: Option<String>,
+    pub schema_key: Option<String>,
+    pub file_id: Option<String>,
+    pub version_id: Option<String>,
+    #[serde(default)]
+    pub schema: Option<Value>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -115,11 +233,26 @@ pub trait HostCallbacks {
     ) -> Result<HostDetectChangesResponse, EngineError>;
 }
 
+/// One structured problem found while validating a mutation row's snapshot
+/// against its stored schema: the offending `schema_key`, a JSON pointer to
+/// the property (or schema keyword) at fault, and a machine-readable
+/// `reason` a caller can switch on without parsing `EngineError::message`.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ValidationIssue {
+    pub entity_id: Option<String>,
+    pub schema_key: String,
+    pub pointer: String,
+    pub reason: &'static str,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "camelCase")]
 pub struct EngineError {
     pub code: &'static str,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub issues: Vec<ValidationIssue>,
 }
 
 impl EngineError {
@@ -127,6 +260,7 @@ impl EngineError {
         Self {
             code,
             message: message.into(),
+            issues: Vec::new(),
         }
     }
 
@@ -134,9 +268,32 @@ impl EngineError {
         Self::new(LIX_RUST_REWRITE_VALIDATION, message)
     }
 
+    /// Batched counterpart to `rewrite_validation`: `issues` is the complete
+    /// set of schema/CEL problems found across every row of a mutation
+    /// statement, collected instead of raised on the first violation so
+    /// callers get every problem in one round trip.
+    fn rewrite_validation_batch(issues: Vec<ValidationIssue>) -> Self {
+        let message = match issues.len() {
+            1 => format!(
+                "snapshot validation failed: {} at {} ({})",
+                issues[0].reason, issues[0].pointer, issues[0].schema_key
+            ),
+            count => format!("snapshot validation failed with {count} problems"),
+        };
+        Self {
+            code: LIX_RUST_REWRITE_VALIDATION,
+            message,
+            issues,
+        }
+    }
+
     fn protocol_mismatch(message: impl Into<String>) -> Self {
         Self::new(LIX_RUST_PROTOCOL_MISMATCH, message)
     }
+
+    fn quota_exceeded(message: impl Into<String>) -> Self {
+        Self::new(LIX_RUST_QUOTA_EXCEEDED, message)
+    }
 }
 
 pub fn execute_with_host(
@@ -149,21 +306,54 @@ pub fn execute_with_host(
     if statement_kind == RUST_KIND_VALIDATION {
         validate_validation_mutations(&request.sql)?;
     }
-    validate_state_mutation_rows(host, &request.sql, request.params.as_slice(), statement_kind)?;
+    let validation_outcome =
+        validate_state_mutation_rows(host, &request.sql, request.params.as_slice(), statement_kind)?;
 
-    let rewritten_sql = rewrite_sql_for_execution(&request.sql, statement_kind)?;
+    let rewritten_sql = rewrite_sql_for_execution_with_schema_defaults(
+        host,
+        &request.sql,
+        statement_kind,
+        request.params.as_slice(),
+        request.as_of.as_ref(),
+    )?;
 
     let should_detect_changes =
-        should_run_plugin_change_detection(statement_kind, &request.sql, request.params.as_slice());
+        should_run_plugin_change_detection(statement_kind, &request.sql, request.params.as_slice())?;
+
+    // A dry run still classifies, rewrites, validates, and runs plugin-change
+    // detection in full — it only skips the `host.execute` call that would
+    // actually mutate state, reporting the rewritten SQL and the projected
+    // touched-row count (asserted + retracted) in its place.
+    let is_dry_run_mutation = request.dry_run
+        && (statement_kind == RUST_KIND_WRITE_REWRITE || statement_kind == RUST_KIND_VALIDATION);
+
+    let (rows, rows_affected, last_insert_row_id, dry_run_rewritten_sql) = if is_dry_run_mutation {
+        let touched = (validation_outcome.transaction_report.asserted.len()
+            + validation_outcome.transaction_report.retracted.len()) as i64;
+        (Vec::new(), touched, None, Some(rewritten_sql))
+    } else {
+        let execute_response = host
+            .execute(HostExecuteRequest {
+                request_id: request.request_id.clone(),
+                sql: rewritten_sql,
+                params: request.params,
+                statement_kind,
+            })
+            .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
 
-    let execute_response = host
-        .execute(HostExecuteRequest {
-            request_id: request.request_id.clone(),
-            sql: rewritten_sql,
-            params: request.params,
-            statement_kind,
-        })
-        .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
+        let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+            execute_response.rows.len() as i64
+        } else {
+            execute_response.rows_affected
+        };
+
+        (
+            execute_response.rows,
+            rows_affected,
+            execute_response.last_insert_row_id,
+            None,
+        )
+    };
 
     let plugin_changes = if should_detect_changes {
         execute_plugin_change_detection(
@@ -175,1078 +365,5179 @@ pub fn execute_with_host(
         Vec::new()
     };
 
-    let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
-        execute_response.rows.len() as i64
-    } else {
-        execute_response.rows_affected
-    };
-
     Ok(ExecuteResult {
         statement_kind,
-        rows: execute_response.rows,
+        rows,
         rows_affected,
-        last_insert_row_id: execute_response.last_insert_row_id,
+        last_insert_row_id,
         plugin_changes,
+        upsert_resolutions: validation_outcome.upsert_resolutions,
+        transaction_report: validation_outcome.transaction_report,
+        rewritten_sql: dry_run_rewritten_sql,
     })
 }
 
-pub fn route_statement_kind(sql: &str) -> &'static str {
-    let dialect = SQLiteDialect {};
-    let parsed = Parser::parse_sql(&dialect, sql);
-
-    let statements = match parsed {
-        Ok(value) if !value.is_empty() => value,
-        _ => return RUST_KIND_PASSTHROUGH,
-    };
-
-    let mut saw_read = false;
-    let mut saw_write = false;
+/// Bounded cache of parsed `Vec<Statement>` keyed by a quote-aware normalized
+/// form of the SQL text, so `plan_execute`/`route_statement_kind`/
+/// `rewrite_sql_for_execution`/`validate_state_mutation_rows` can share a
+/// single `Parser::parse_sql` call for a repeated `request_id`/SQL shape
+/// instead of each re-parsing it. Eviction is least-recently-used; capacity 0
+/// disables caching entirely (every lookup is a miss).
+pub struct ParseCache {
+    capacity: usize,
+    entries: std::sync::Mutex<Vec<(String, std::sync::Arc<Vec<Statement>>)>>,
+}
 
-    for statement in statements {
-        match statement {
-            Statement::Query(_) => {
-                saw_read = true;
-            }
-            Statement::Insert(_) => {
-                saw_write = true;
-            }
-            Statement::Update { .. } => {
-                saw_write = true;
-            }
-            Statement::Delete(_) => {
-                saw_write = true;
-            }
-            _ => {
-                return RUST_KIND_PASSTHROUGH;
-            }
+impl ParseCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: std::sync::Mutex::new(Vec::new()),
         }
     }
 
-    if saw_write {
-        if is_validation_sql(sql) {
-            return RUST_KIND_VALIDATION;
-        }
-        return RUST_KIND_WRITE_REWRITE;
+    /// A cache that never stores anything, for one-shot statements where
+    /// paying to maintain the cache isn't worth it.
+    pub fn disabled() -> Self {
+        Self::with_capacity(0)
     }
 
-    if saw_read {
-        return RUST_KIND_READ_REWRITE;
+    fn get(&self, key: &str) -> Option<std::sync::Arc<Vec<Statement>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries.iter().position(|(existing, _)| existing == key)?;
+        let (key, value) = entries.remove(position);
+        entries.push((key, value.clone()));
+        Some(value)
     }
 
-    RUST_KIND_PASSTHROUGH
+    fn insert(&self, key: String, value: std::sync::Arc<Vec<Statement>>) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.remove(0);
+        }
+        entries.push((key, value));
+    }
 }
 
-pub fn plan_execute(sql: &str) -> ExecutePlan {
-    let statement_kind = route_statement_kind(sql);
-    let preprocess_mode = if statement_kind == RUST_KIND_PASSTHROUGH {
-        "none"
-    } else {
-        "full"
-    };
-    let rows_affected_mode =
-        if statement_kind == RUST_KIND_READ_REWRITE || statement_kind == RUST_KIND_PASSTHROUGH {
-            RUST_ROWS_AFFECTED_ROWS_LENGTH
-        } else {
-            RUST_ROWS_AFFECTED_SQLITE_CHANGES
-        };
-    ExecutePlan {
-        statement_kind,
-        preprocess_mode,
-        rows_affected_mode,
+/// Normalizes SQL for use as a `ParseCache` key: trims surrounding
+/// whitespace, collapses whitespace runs, and lowercases everything outside
+/// single/double-quoted literals, so cosmetic differences share a cache
+/// entry while literal contents (which affect the parsed AST) still
+/// distinguish entries.
+fn normalize_for_parse_cache(sql: &str) -> String {
+    let mut normalized = String::with_capacity(sql.len());
+    let mut last_was_space = false;
+    let mut quote: Option<char> = None;
+
+    for ch in sql.trim().chars() {
+        if let Some(open) = quote {
+            normalized.push(ch);
+            if ch == open {
+                quote = None;
+            }
+            last_was_space = false;
+            continue;
+        }
+        if ch == '\'' || ch == '"' {
+            quote = Some(ch);
+            normalized.push(ch);
+            last_was_space = false;
+            continue;
+        }
+        if ch.is_whitespace() {
+            if !last_was_space {
+                normalized.push(' ');
+                last_was_space = true;
+            }
+            continue;
+        }
+        normalized.extend(ch.to_lowercase());
+        last_was_space = false;
     }
-}
 
-fn is_validation_sql(sql: &str) -> bool {
-    let lowered = sql.to_lowercase();
-    lowered.contains("insert into state")
-        || lowered.contains("insert into state_all")
-        || lowered.contains("update state")
-        || lowered.contains("update state_all")
-        || lowered.contains("delete from state")
-        || lowered.contains("delete from state_all")
+    normalized
 }
 
-pub fn rewrite_sql_for_execution(sql: &str, statement_kind: &str) -> Result<String, EngineError> {
-    if statement_kind == RUST_KIND_PASSTHROUGH {
-        return Ok(sql.to_owned());
+fn parse_cached(cache: &ParseCache, sql: &str) -> Result<std::sync::Arc<Vec<Statement>>, EngineError> {
+    let key = normalize_for_parse_cache(sql);
+    if let Some(cached) = cache.get(&key) {
+        return Ok(cached);
     }
 
     let dialect = SQLiteDialect {};
-    let parsed = Parser::parse_sql(&dialect, sql).map_err(|error| {
-        EngineError::protocol_mismatch(format!("failed to parse SQL for rewrite: {error}"))
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse SQL: {error}"))
     })?;
+    let statements = std::sync::Arc::new(statements);
+    cache.insert(key, statements.clone());
+    Ok(statements)
+}
 
-    if parsed.is_empty() {
-        return Err(EngineError::protocol_mismatch(
-            "expected at least one statement for rewrite",
-        ));
-    }
-
-    let mut rewritten_statements: Vec<String> = Vec::with_capacity(parsed.len());
-    let mut changed = false;
-    for statement in &parsed {
-        let (rewritten, statement_changed) = match statement_kind {
-            RUST_KIND_READ_REWRITE => {
-                let mut statement_clone = statement.clone();
-                let statement_changed = rewrite_statement_for_read_rewrite(&mut statement_clone)?;
-                (statement_clone.to_string(), statement_changed)
-            }
-            RUST_KIND_WRITE_REWRITE | RUST_KIND_VALIDATION => {
-                rewrite_statement_for_write_rewrite(statement)?
-            }
-            _ => (statement.to_string(), false),
-        };
-        rewritten_statements.push(rewritten);
-        changed |= statement_changed;
-    }
-
-    if !changed {
-        return Ok(sql.to_owned());
-    }
+/// The routed/rewritten form of a normalized SQL shape, as produced by
+/// `QueryPlanCache::allocate` and returned by `QueryPlanCache::lookup`.
+#[derive(Debug, Clone)]
+pub struct CachedQueryPlan {
+    pub statement_kind: &'static str,
+    pub preprocess_mode: &'static str,
+    pub rows_affected_mode: &'static str,
+    pub rewritten_sql: String,
+}
 
-    Ok(rewritten_statements.join("; "))
+/// Cache of routed/rewritten query plans keyed by a *normalized* form of the
+/// SQL text (literal `Value::Number`/`Value::SingleQuotedString`/
+/// `Value::Boolean` leaves replaced with `?` placeholders), modeled on
+/// isomorphicdb's `QueryPlanCache::allocate/lookup/deallocate`. Unlike
+/// `ParseCache`, which caches the parsed `Vec<Statement>` for a single exact
+/// SQL string, this cache collapses every differently-parameterized instance
+/// of the same statement shape (`values ('a')` vs `values ('b')`) onto one
+/// entry, so repeat executions of the same shape skip parsing and rewriting
+/// entirely. Eviction is least-recently-used; capacity 0 disables caching.
+pub struct QueryPlanCache {
+    capacity: usize,
+    entries: std::sync::Mutex<Vec<(String, CachedQueryPlan)>>,
 }
 
-fn rewrite_statement_for_read_rewrite(statement: &mut Statement) -> Result<bool, EngineError> {
-    match statement {
-        Statement::Query(query) => rewrite_query_for_read_rewrite(query),
-        _ => Ok(false),
+impl Default for QueryPlanCache {
+    fn default() -> Self {
+        Self::with_capacity(256)
     }
 }
 
-fn rewrite_query_for_read_rewrite(query: &mut Query) -> Result<bool, EngineError> {
-    let mut changed = false;
-
-    if let Some(with_clause) = &mut query.with {
-        for cte in &mut with_clause.cte_tables {
-            changed |= rewrite_query_for_read_rewrite(&mut cte.query)?;
+impl QueryPlanCache {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: std::sync::Mutex::new(Vec::new()),
         }
     }
 
-    changed |= rewrite_set_expr_for_read_rewrite(&mut query.body)?;
-    Ok(changed)
-}
+    pub fn disabled() -> Self {
+        Self::with_capacity(0)
+    }
 
-fn rewrite_set_expr_for_read_rewrite(set_expr: &mut SetExpr) -> Result<bool, EngineError> {
-    match set_expr {
-        SetExpr::Select(select) => rewrite_select_for_read_rewrite(select),
-        SetExpr::Query(query) => rewrite_query_for_read_rewrite(query),
-        SetExpr::SetOperation { left, right, .. } => {
-            let left_changed = rewrite_set_expr_for_read_rewrite(left)?;
-            let right_changed = rewrite_set_expr_for_read_rewrite(right)?;
-            Ok(left_changed || right_changed)
+    /// Normalizes `sql` to its placeholder-stripped shape, routes and
+    /// rewrites it if that shape isn't already cached, and returns the
+    /// normalized key alongside the resulting plan. Bound literal values are
+    /// not part of the key or the cached plan; callers recover them
+    /// separately with `extract_plan_cache_literals` and carry them the way
+    /// placeholders already are, via `params`.
+    pub fn allocate(&self, sql: &str, as_of: Option<&AsOf>) -> Result<(String, CachedQueryPlan), EngineError> {
+        let key = normalize_sql_for_plan_cache_key(sql)?;
+        if let Some(plan) = self.lookup(&key) {
+            return Ok((key, plan));
         }
-        _ => Ok(false),
-    }
-}
 
-fn rewrite_select_for_read_rewrite(select: &mut Select) -> Result<bool, EngineError> {
-    let mut changed = false;
-    for table_with_joins in &mut select.from {
-        changed |= rewrite_table_with_joins_for_read_rewrite(table_with_joins)?;
+        // Route and rewrite the *normalized* shape, not `sql` itself, so the
+        // cached plan's placeholders line up with `extract_plan_cache_literals`
+        // regardless of which call's literal values happened to populate it.
+        let statement_kind = route_statement_kind(&key);
+        let plan = build_execute_plan(statement_kind, sql_has_returning_clause(&key));
+        let rewritten_sql = rewrite_sql_for_execution_as_of(&key, statement_kind, as_of)?;
+        let cached = CachedQueryPlan {
+            statement_kind,
+            preprocess_mode: plan.preprocess_mode,
+            rows_affected_mode: plan.rows_affected_mode,
+            rewritten_sql,
+        };
+        self.insert(key.clone(), cached.clone());
+        Ok((key, cached))
     }
-    Ok(changed)
-}
-
-fn rewrite_table_with_joins_for_read_rewrite(
-    table_with_joins: &mut TableWithJoins,
-) -> Result<bool, EngineError> {
-    let mut changed = rewrite_table_factor_for_read_rewrite(&mut table_with_joins.relation)?;
 
-    for join in &mut table_with_joins.joins {
-        changed |= rewrite_table_factor_for_read_rewrite(&mut join.relation)?;
+    pub fn lookup(&self, key: &str) -> Option<CachedQueryPlan> {
+        let mut entries = self.entries.lock().unwrap();
+        let position = entries.iter().position(|(existing, _)| existing == key)?;
+        let (key, value) = entries.remove(position);
+        entries.push((key, value.clone()));
+        Some(value)
     }
 
-    Ok(changed)
-}
+    pub fn deallocate(&self, key: &str) {
+        self.entries.lock().unwrap().retain(|(existing, _)| existing != key);
+    }
 
-fn rewrite_table_factor_for_read_rewrite(
-    table_factor: &mut TableFactor,
-) -> Result<bool, EngineError> {
-    match table_factor {
-        TableFactor::Table {
-            name, alias, args, ..
-        } => {
-            if args.is_some() || !is_target_vtable_name(name) {
-                return Ok(false);
-            }
+    /// Drops every cached plan. A cached plan's rewrite can embed
+    /// schema-dependent behavior (e.g. validation short-circuits), so callers
+    /// should invalidate the whole cache whenever `stored_schema` changes
+    /// rather than trying to track which shapes were affected.
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
 
-            let subquery = build_state_vtable_equivalent_subquery()?;
-            let derived_alias = alias.take().unwrap_or_else(|| TableAlias {
-                name: Ident::new(INTERNAL_STATE_VTABLE),
-                columns: Vec::new(),
-            });
-            *table_factor = TableFactor::Derived {
-                lateral: false,
-                subquery: Box::new(subquery),
-                alias: Some(derived_alias),
-            };
-            Ok(true)
+    fn insert(&self, key: String, value: CachedQueryPlan) {
+        if self.capacity == 0 {
+            return;
         }
-        TableFactor::Derived { subquery, .. } => rewrite_query_for_read_rewrite(subquery),
-        TableFactor::NestedJoin {
-            table_with_joins, ..
-        } => rewrite_table_with_joins_for_read_rewrite(table_with_joins),
-        TableFactor::Pivot { table, .. } => rewrite_table_factor_for_read_rewrite(table),
-        TableFactor::Unpivot { table, .. } => rewrite_table_factor_for_read_rewrite(table),
-        TableFactor::MatchRecognize { table, .. } => rewrite_table_factor_for_read_rewrite(table),
-        _ => Ok(false),
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.remove(0);
+        }
+        entries.push((key, value));
     }
 }
 
-fn is_target_vtable_name(name: &ObjectName) -> bool {
-    name.0
-        .last()
-        .map(|part| part.value.eq_ignore_ascii_case(INTERNAL_STATE_VTABLE))
-        .unwrap_or(false)
-}
-
-fn build_state_vtable_equivalent_subquery() -> Result<Query, EngineError> {
+/// Computes `QueryPlanCache`'s normalization key for `sql`: parses it, walks
+/// each statement's INSERT `VALUES` rows, `UPDATE`/`DELETE`/`SELECT` WHERE
+/// equalities, and `json(...)` arguments, replacing literal leaves with `?`
+/// placeholders, then re-renders the statements. Expressions outside that
+/// narrow, well-understood shape are left untouched, so they still
+/// distinguish cache entries by their literal text rather than collapsing
+/// (a conservative fallback, not a correctness issue).
+fn normalize_sql_for_plan_cache_key(sql: &str) -> Result<String, EngineError> {
     let dialect = SQLiteDialect {};
-    let statements = Parser::parse_sql(
-        &dialect,
-        "SELECT \
-            entity_id, \
-            schema_key, \
-            file_id, \
-            version_id, \
-            plugin_key, \
-            snapshot_content, \
-            schema_version, \
-            created_at, \
-            updated_at, \
-            inherited_from_version_id, \
-            NULL AS change_id, \
-            1 AS untracked, \
-            NULL AS commit_id, \
-            NULL AS writer_key, \
-            NULL AS metadata \
-        FROM lix_internal_state_all_untracked",
-    )
-    .map_err(|error| {
-        EngineError::protocol_mismatch(format!(
-            "failed to construct read rewrite for {INTERNAL_STATE_VTABLE}: {error}"
-        ))
+    let mut statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse SQL for plan cache: {error}"))
     })?;
 
-    let statement = statements.into_iter().next().ok_or_else(|| {
-        EngineError::protocol_mismatch(format!(
-            "missing read rewrite statement for {INTERNAL_STATE_VTABLE}"
-        ))
-    })?;
-
-    match statement {
-        Statement::Query(query) => Ok(*query),
-        _ => Err(EngineError::protocol_mismatch(format!(
-            "read rewrite query for {INTERNAL_STATE_VTABLE} must be a SELECT"
-        ))),
+    let mut discarded_literals = Vec::new();
+    for statement in &mut statements {
+        normalize_statement_literals(statement, &mut discarded_literals);
     }
+
+    Ok(statements
+        .iter()
+        .map(Statement::to_string)
+        .collect::<Vec<_>>()
+        .join("; "))
 }
 
-fn validate_validation_mutations(sql: &str) -> Result<(), EngineError> {
+/// Same AST walk as `normalize_sql_for_plan_cache_key`, but also resolves
+/// every `?` in the normalized SQL (both literals it strips out and
+/// placeholders already present in `sql`) against `params`, in left-to-right
+/// order, so the returned values can be bound directly to the cached
+/// `rewritten_sql`.
+fn extract_plan_cache_literals(sql: &str, params: &[Value]) -> Result<Vec<Value>, EngineError> {
     let dialect = SQLiteDialect {};
-    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
-        EngineError::rewrite_validation(format!("failed to parse validation SQL: {error}"))
+    let mut statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse SQL for plan cache: {error}"))
     })?;
 
-    if statements.is_empty() {
-        return Err(EngineError::rewrite_validation(
-            "validation SQL must include at least one mutation statement",
-        ));
+    let mut param_cursor: usize = 0;
+    let mut literals = Vec::new();
+    for statement in &mut statements {
+        normalize_statement_literals_with_params(
+            statement,
+            params,
+            &mut param_cursor,
+            &mut literals,
+        )?;
     }
+    Ok(literals)
+}
 
-    for statement in statements {
-        if !is_validation_mutation_statement(&statement) {
-            return Err(EngineError::rewrite_validation(
-                "validation statements may only mutate state or state_all",
-            ));
+fn normalize_statement_literals(statement: &mut Statement, literals: &mut Vec<Value>) {
+    // Key computation doesn't need resolved values, only the `?` shape, so
+    // errors from unbound parameters can't occur here; discard them.
+    let placeholders = vec![Value::Null; statement.to_string().matches('?').count()];
+    let _ = normalize_statement_literals_with_params(statement, &placeholders, &mut 0, literals);
+}
+
+fn normalize_statement_literals_with_params(
+    statement: &mut Statement,
+    params: &[Value],
+    param_cursor: &mut usize,
+    literals: &mut Vec<Value>,
+) -> Result<(), EngineError> {
+    match statement {
+        Statement::Insert(insert) => {
+            let Some(source) = insert.source.as_mut() else {
+                return Ok(());
+            };
+            let SetExpr::Values(values) = source.body.as_mut() else {
+                return Ok(());
+            };
+            for row in &mut values.rows {
+                for expr in row {
+                    normalize_expr_literal(expr, params, param_cursor, literals)?;
+                }
+            }
+        }
+        Statement::Update {
+            assignments,
+            selection,
+            ..
+        } => {
+            for assignment in assignments {
+                normalize_expr_literal(&mut assignment.value, params, param_cursor, literals)?;
+            }
+            if let Some(selection) = selection {
+                normalize_where_literals(selection, params, param_cursor, literals)?;
+            }
+        }
+        Statement::Delete(delete) => {
+            if let Some(selection) = &mut delete.selection {
+                normalize_where_literals(selection, params, param_cursor, literals)?;
+            }
+        }
+        Statement::Query(query) => {
+            if let SetExpr::Select(select) = query.body.as_mut() {
+                if let Some(selection) = &mut select.selection {
+                    normalize_where_literals(selection, params, param_cursor, literals)?;
+                }
+            }
         }
+        _ => {}
     }
+    Ok(())
+}
 
+/// Walks `AND`-joined equality clauses (the shape subscription predicates
+/// already assume) and normalizes the value side of each equality.
+fn normalize_where_literals(
+    expr: &mut Expr,
+    params: &[Value],
+    param_cursor: &mut usize,
+    literals: &mut Vec<Value>,
+) -> Result<(), EngineError> {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: sqlparser::ast::BinaryOperator::And,
+            right,
+        } => {
+            normalize_where_literals(left, params, param_cursor, literals)?;
+            normalize_where_literals(right, params, param_cursor, literals)?;
+        }
+        Expr::BinaryOp {
+            left,
+            op: sqlparser::ast::BinaryOperator::Eq,
+            right,
+        } => {
+            if matches!(left.as_ref(), Expr::Identifier(_)) {
+                normalize_expr_literal(right, params, param_cursor, literals)?;
+            } else if matches!(right.as_ref(), Expr::Identifier(_)) {
+                normalize_expr_literal(left, params, param_cursor, literals)?;
+            }
+        }
+        _ => {}
+    }
     Ok(())
 }
 
-fn is_validation_mutation_statement(statement: &Statement) -> bool {
-    match statement {
-        Statement::Insert(insert) => is_validation_target_name(&insert.table_name),
-        Statement::Update { table, .. } => {
-            let TableFactor::Table { name, .. } = &table.relation else {
-                return false;
-            };
-            is_validation_target_name(name)
+/// Replaces `expr` with a `?` placeholder if it's a literal value or a
+/// `json(...)` call wrapping one, pushing the value it resolved to onto
+/// `literals`. Leaves any other expression shape untouched and pushes
+/// nothing, so callers can tell from `literals`'s length how many
+/// placeholders they actually produced versus left embedded in the text.
+fn normalize_expr_literal(
+    expr: &mut Expr,
+    params: &[Value],
+    param_cursor: &mut usize,
+    literals: &mut Vec<Value>,
+) -> Result<(), EngineError> {
+    match expr {
+        Expr::Value(sqlparser::ast::Value::Placeholder(_)) => {
+            literals.push(convert_sql_value_to_json(
+                &sqlparser::ast::Value::Placeholder("?".to_owned()),
+                params,
+                param_cursor,
+                false,
+            )?);
         }
-        Statement::Delete(delete) => {
-            let tables = match &delete.from {
-                FromTable::WithFromKeyword(value) => value,
-                FromTable::WithoutKeyword(value) => value,
-            };
-            let Some(first) = tables.first() else {
-                return false;
+        Expr::Value(value @ (sqlparser::ast::Value::Number(_, _)
+        | sqlparser::ast::Value::SingleQuotedString(_)
+        | sqlparser::ast::Value::Boolean(_))) => {
+            let resolved = convert_sql_value_to_json(value, params, param_cursor, false)?;
+            literals.push(resolved);
+            *value = sqlparser::ast::Value::Placeholder("?".to_owned());
+        }
+        Expr::Function(function) if function.name.to_string().eq_ignore_ascii_case("json") => {
+            let FunctionArguments::List(argument_list) = &mut function.args else {
+                return Ok(());
             };
-            let TableFactor::Table { name, .. } = &first.relation else {
-                return false;
+            if argument_list.args.len() != 1 {
+                return Ok(());
+            }
+            let FunctionArg::Unnamed(FunctionArgExpr::Expr(inner)) = &mut argument_list.args[0]
+            else {
+                return Ok(());
             };
-            is_validation_target_name(name)
+            normalize_expr_literal(inner, params, param_cursor, literals)?;
         }
-        _ => false,
+        _ => {}
     }
+    Ok(())
 }
 
-fn is_validation_target_name(name: &ObjectName) -> bool {
-    matches!(
-        classify_write_target(name),
-        WriteTarget::State
-            | WriteTarget::StateAll
-            | WriteTarget::StateByVersion
-            | WriteTarget::StateVtable
-    )
+/// Opaque handle returned by `PreparedStatementCache::allocate`, echoing the
+/// name the plan was registered under.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanHandle {
+    pub name: String,
 }
 
-#[derive(Debug, Clone)]
-struct MutationValidationRow {
-    schema_key: String,
-    schema_version: String,
-    snapshot_content: Value,
+/// A plan cache keyed by an explicit caller-supplied name rather than SQL
+/// shape or text, for the classic "prepare once, execute many" workflow:
+/// `allocate(name, sql)` routes and rewrites `sql` exactly once (`sql` is
+/// expected to already use `?` placeholders for anything that varies between
+/// executions) and stores the result under `name`; later executions look the
+/// plan up by `name` alone and bind fresh `params` into it, skipping
+/// `Parser::parse_sql` and the rewrite walk entirely. Unlike `QueryPlanCache`,
+/// which normalizes arbitrary SQL into a shape key and self-evicts LRU-style,
+/// callers here own the name's lifecycle and must `deallocate` it themselves
+/// when done, or `invalidate_all` when `stored_schema` changes underneath it.
+pub struct PreparedStatementCache {
+    entries: std::sync::Mutex<std::collections::HashMap<String, CachedQueryPlan>>,
 }
 
-fn validate_state_mutation_rows(
-    host: &dyn HostCallbacks,
-    sql: &str,
-    params: &[Value],
-    statement_kind: &str,
-) -> Result<(), EngineError> {
-    let should_validate = statement_kind == RUST_KIND_VALIDATION
-        || (statement_kind == RUST_KIND_WRITE_REWRITE && might_mutate_state_tables(sql));
-    if !should_validate {
-        return Ok(());
+impl Default for PreparedStatementCache {
+    fn default() -> Self {
+        Self {
+            entries: std::sync::Mutex::new(std::collections::HashMap::new()),
+        }
     }
+}
 
-    let dialect = SQLiteDialect {};
-    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
-        EngineError::rewrite_validation(format!("failed to parse mutation SQL for validation: {error}"))
-    })?;
+impl PreparedStatementCache {
+    pub fn allocate(
+        &self,
+        name: &str,
+        sql: &str,
+        as_of: Option<&AsOf>,
+    ) -> Result<PlanHandle, EngineError> {
+        let statement_kind = route_statement_kind(sql);
+        let plan = build_execute_plan(statement_kind, sql_has_returning_clause(sql));
+        let rewritten_sql = rewrite_sql_for_execution_as_of(sql, statement_kind, as_of)?;
+        let cached = CachedQueryPlan {
+            statement_kind,
+            preprocess_mode: plan.preprocess_mode,
+            rows_affected_mode: plan.rows_affected_mode,
+            rewritten_sql,
+        };
+        self.entries.lock().unwrap().insert(name.to_owned(), cached);
+        Ok(PlanHandle {
+            name: name.to_owned(),
+        })
+    }
 
-    let mut param_cursor: usize = 0;
-    for statement in &statements {
-        let mut rows = extract_insert_validation_rows(statement, params, &mut param_cursor)?;
-        for row in rows.drain(..) {
-            validate_single_mutation_row(host, &row)?;
-        }
+    pub fn lookup(&self, name: &str) -> Option<CachedQueryPlan> {
+        self.entries.lock().unwrap().get(name).cloned()
     }
 
-    Ok(())
-}
+    pub fn deallocate(&self, name: &str) {
+        self.entries.lock().unwrap().remove(name);
+    }
 
-fn might_mutate_state_tables(sql: &str) -> bool {
-    let lowered = sql.to_lowercase();
-    lowered.contains("insert into state")
-        || lowered.contains("insert into state_by_version")
-        || lowered.contains("insert into state_all")
-        || lowered.contains("insert into lix_internal_state_vtable")
-        || lowered.contains("update state")
-        || lowered.contains("update state_by_version")
-        || lowered.contains("update state_all")
-        || lowered.contains("update lix_internal_state_vtable")
-        || lowered.contains("delete from state")
-        || lowered.contains("delete from state_by_version")
-        || lowered.contains("delete from state_all")
-        || lowered.contains("delete from lix_internal_state_vtable")
+    /// Drops every registered plan. A cached plan's rewrite can embed
+    /// schema-dependent behavior (e.g. validation short-circuits), so callers
+    /// should invalidate every prepared name whenever `stored_schema`
+    /// changes rather than trying to track which ones were affected.
+    pub fn invalidate_all(&self) {
+        self.entries.lock().unwrap().clear();
+    }
 }
 
-fn extract_insert_validation_rows(
-    statement: &Statement,
-    params: &[Value],
-    param_cursor: &mut usize,
-) -> Result<Vec<MutationValidationRow>, EngineError> {
-    let Statement::Insert(insert) = statement else {
-        return Ok(Vec::new());
+/// Same pipeline as `execute_with_host`, but resolves the rewritten SQL and
+/// statement kind from `prepared_cache` under `request.prepared_name`
+/// instead of rewriting `request.sql` itself, so a statement prepared once
+/// via `PreparedStatementCache::allocate` turns every later execution into a
+/// bind-and-execute with no parse/rewrite cost. Validation and plugin change
+/// detection still run against `request.sql`/`request.params` every time,
+/// the same tradeoff `execute_with_host_and_plan_cache` makes, since those
+/// depend on the actual row contents rather than just the statement shape.
+/// Falls back to `execute_with_host` entirely when `request.prepared_name`
+/// is `None`.
+pub fn execute_with_host_and_prepared_cache(
+    prepared_cache: &PreparedStatementCache,
+    host: &dyn HostCallbacks,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let Some(name) = request.prepared_name.clone() else {
+        return execute_with_host(host, request);
     };
+    let plan = prepared_cache.lookup(&name).ok_or_else(|| {
+        EngineError::protocol_mismatch(format!(
+            "no prepared plan registered for name `{name}`"
+        ))
+    })?;
 
-    if !is_validation_target_name(&insert.table_name) {
-        return Ok(Vec::new());
+    if plan.statement_kind == RUST_KIND_VALIDATION {
+        validate_validation_mutations(&request.sql)?;
     }
+    let validation_outcome = validate_state_mutation_rows(
+        host,
+        &request.sql,
+        request.params.as_slice(),
+        plan.statement_kind,
+    )?;
+
+    let should_detect_changes = should_run_plugin_change_detection(
+        plan.statement_kind,
+        &request.sql,
+        request.params.as_slice(),
+    )?;
 
-    let Some(source) = &insert.source else {
-        return Ok(Vec::new());
-    };
-    let SetExpr::Values(values) = &*source.body else {
-        return Ok(Vec::new());
+    let execute_response = host
+        .execute(HostExecuteRequest {
+            request_id: request.request_id.clone(),
+            sql: plan.rewritten_sql.clone(),
+            params: request.params,
+            statement_kind: plan.statement_kind,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
+
+    let plugin_changes = if should_detect_changes {
+        execute_plugin_change_detection(
+            host,
+            &request.request_id,
+            request.plugin_change_requests.as_slice(),
+        )?
+    } else {
+        Vec::new()
     };
 
-    let column_names: Vec<String> = if insert.columns.is_empty() {
-        vec![
-            "entity_id".to_owned(),
-            "schema_key".to_owned(),
-            "file_id".to_owned(),
-            "plugin_key".to_owned(),
-            "snapshot_content".to_owned(),
-            "schema_version".to_owned(),
-            "metadata".to_owned(),
-            "untracked".to_owned(),
-            "version_id".to_owned(),
-        ]
+    let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+        execute_response.rows.len() as i64
     } else {
-        insert
-            .columns
-            .iter()
-            .map(|ident| ident.value.to_lowercase())
-            .collect()
+        execute_response.rows_affected
     };
 
-    let schema_key_idx = column_names
-        .iter()
-        .position(|name| name == "schema_key")
-        .ok_or_else(|| {
-            EngineError::rewrite_validation("state mutation missing required schema_key column")
-        })?;
-    let schema_version_idx = column_names
-        .iter()
-        .position(|name| name == "schema_version")
-        .ok_or_else(|| {
-            EngineError::rewrite_validation("state mutation missing required schema_version column")
-        })?;
-    let snapshot_idx = column_names
-        .iter()
-        .position(|name| name == "snapshot_content")
-        .ok_or_else(|| {
-            EngineError::rewrite_validation("state mutation missing required snapshot_content column")
-        })?;
+    Ok(ExecuteResult {
+        statement_kind: plan.statement_kind,
+        rows: execute_response.rows,
+        rows_affected,
+        last_insert_row_id: execute_response.last_insert_row_id,
+        plugin_changes,
+        upsert_resolutions: validation_outcome.upsert_resolutions,
+        transaction_report: validation_outcome.transaction_report,
+        rewritten_sql: None,
+    })
+}
 
-    let mut result = Vec::with_capacity(values.rows.len());
-    for row in &values.rows {
-        if row.len() != column_names.len() {
-            return Err(EngineError::rewrite_validation(
-                "insert row shape does not match declared columns",
-            ));
-        }
+/// Same pipeline as `execute_with_host`, but shares `cache`'s parsed
+/// statements across routing, rewriting, and validation instead of parsing
+/// the same SQL three or four times per call.
+pub fn execute_with_host_cached(
+    cache: &ParseCache,
+    host: &dyn HostCallbacks,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let plan = plan_execute_cached(cache, &request.sql);
+    let statement_kind = plan.statement_kind;
 
-        let schema_key =
-            evaluate_sql_expr_to_json(&row[schema_key_idx], params, param_cursor, false)?;
-        let schema_version = evaluate_sql_expr_to_json(
-            &row[schema_version_idx],
-            params,
-            param_cursor,
-            false,
-        )?;
-        let snapshot_content =
-            evaluate_sql_expr_to_json(&row[snapshot_idx], params, param_cursor, true)?;
+    if statement_kind == RUST_KIND_VALIDATION {
+        validate_validation_mutations(&request.sql)?;
+    }
+    let validation_outcome = validate_state_mutation_rows_cached(
+        cache,
+        host,
+        &request.sql,
+        request.params.as_slice(),
+        statement_kind,
+    )?;
 
-        let schema_key = schema_key.as_str().ok_or_else(|| {
-            EngineError::rewrite_validation("schema_key must resolve to a string")
-        })?;
-        let schema_version = schema_version.as_str().ok_or_else(|| {
-            EngineError::rewrite_validation("schema_version must resolve to a string")
-        })?;
+    let rewritten_sql = rewrite_sql_for_execution_as_of_cached(
+        cache,
+        &request.sql,
+        statement_kind,
+        request.as_of.as_ref(),
+    )?;
 
-        result.push(MutationValidationRow {
-            schema_key: schema_key.to_owned(),
-            schema_version: schema_version.to_owned(),
-            snapshot_content,
-        });
-    }
+    let should_detect_changes =
+        should_run_plugin_change_detection(statement_kind, &request.sql, request.params.as_slice())?;
 
-    Ok(result)
-}
+    let execute_response = host
+        .execute(HostExecuteRequest {
+            request_id: request.request_id.clone(),
+            sql: rewritten_sql,
+            params: request.params,
+            statement_kind,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
 
-fn evaluate_sql_expr_to_json(
-    expr: &Expr,
-    params: &[Value],
-    param_cursor: &mut usize,
-    parse_json_strings: bool,
-) -> Result<Value, EngineError> {
-    match expr {
-        Expr::Value(value) => convert_sql_value_to_json(value, params, param_cursor, parse_json_strings),
-        Expr::Function(function) => {
-            let function_name = function.name.to_string().to_lowercase();
-            if function_name == "json" {
-                let FunctionArguments::List(argument_list) = &function.args else {
-                    return Err(EngineError::rewrite_validation(
-                        "json(...) requires an argument list",
-                    ));
-                };
-                if argument_list.args.len() != 1 {
-                    return Err(EngineError::rewrite_validation(
-                        "json(...) requires exactly one argument",
-                    ));
-                }
-                let FunctionArg::Unnamed(FunctionArgExpr::Expr(inner)) = &argument_list.args[0]
-                else {
-                    return Err(EngineError::rewrite_validation(
-                        "json(...) only supports expression arguments in Rust validation",
-                    ));
-                };
-                let value = evaluate_sql_expr_to_json(inner, params, param_cursor, true)?;
-                return Ok(value);
-            }
+    let plugin_changes = if should_detect_changes {
+        execute_plugin_change_detection(
+            host,
+            &request.request_id,
+            request.plugin_change_requests.as_slice(),
+        )?
+    } else {
+        Vec::new()
+    };
 
-            Err(EngineError::rewrite_validation(format!(
-                "unsupported SQL function in state validation mutation: {function_name}"
-            )))
+    let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+        execute_response.rows.len() as i64
+    } else {
+        execute_response.rows_affected
+    };
+
+    Ok(ExecuteResult {
+        statement_kind,
+        rows: execute_response.rows,
+        rows_affected,
+        last_insert_row_id: execute_response.last_insert_row_id,
+        plugin_changes,
+        upsert_resolutions: validation_outcome.upsert_resolutions,
+        transaction_report: validation_outcome.transaction_report,
+        rewritten_sql: None,
+    })
+}
+
+/// Same pipeline as `execute_with_host`, but routes and rewrites through
+/// `plan_cache` so repeat executions of the same normalized SQL shape (same
+/// structure, any literal values) skip `Parser::parse_sql` and the rewrite
+/// walk entirely. Validation and plugin change detection still run against
+/// `request.sql`'s own literal values every time, since those depend on the
+/// actual row contents, not just the statement shape.
+pub fn execute_with_host_and_plan_cache(
+    plan_cache: &QueryPlanCache,
+    host: &dyn HostCallbacks,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let (_, plan) = plan_cache.allocate(&request.sql, request.as_of.as_ref())?;
+
+    if plan.statement_kind == RUST_KIND_VALIDATION {
+        validate_validation_mutations(&request.sql)?;
+    }
+    let validation_outcome = validate_state_mutation_rows(
+        host,
+        &request.sql,
+        request.params.as_slice(),
+        plan.statement_kind,
+    )?;
+
+    let bound_params = extract_plan_cache_literals(&request.sql, request.params.as_slice())?;
+
+    let should_detect_changes = should_run_plugin_change_detection(
+        plan.statement_kind,
+        &request.sql,
+        request.params.as_slice(),
+    )?;
+
+    let execute_response = host
+        .execute(HostExecuteRequest {
+            request_id: request.request_id.clone(),
+            sql: plan.rewritten_sql.clone(),
+            params: bound_params,
+            statement_kind: plan.statement_kind,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
+
+    let plugin_changes = if should_detect_changes {
+        execute_plugin_change_detection(
+            host,
+            &request.request_id,
+            request.plugin_change_requests.as_slice(),
+        )?
+    } else {
+        Vec::new()
+    };
+
+    let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+        execute_response.rows.len() as i64
+    } else {
+        execute_response.rows_affected
+    };
+
+    Ok(ExecuteResult {
+        statement_kind: plan.statement_kind,
+        rows: execute_response.rows,
+        rows_affected,
+        last_insert_row_id: execute_response.last_insert_row_id,
+        plugin_changes,
+        upsert_resolutions: validation_outcome.upsert_resolutions,
+        transaction_report: validation_outcome.transaction_report,
+        rewritten_sql: None,
+    })
+}
+
+/// Per-statement change in row counts, grouped by the `(schema_key)` and
+/// `(version_id)` buckets `QuotaTracker` enforces, plus the net change to the
+/// table-wide total. Built by `compute_mutation_row_deltas` from the same
+/// `__lix_mutation_rows` shape `extract_insert_validation_rows`/
+/// `extract_delete_effect_rows` already parse for validation, plus a
+/// point lookup per conflicting/deleted row to resolve whether it actually
+/// creates or removes a row — no table scan.
+#[derive(Debug, Default, Clone)]
+struct MutationRowDeltas {
+    schema_key: std::collections::HashMap<String, i64>,
+    version_id: std::collections::HashMap<String, i64>,
+    total: i64,
+}
+
+impl MutationRowDeltas {
+    fn add(&mut self, schema_key: Option<&str>, version_id: Option<&str>, delta: i64) {
+        if let Some(schema_key) = schema_key {
+            *self.schema_key.entry(schema_key.to_owned()).or_insert(0) += delta;
         }
-        _ => Err(EngineError::rewrite_validation(format!(
-            "unsupported SQL expression in validation mutation: {expr}"
-        ))),
+        if let Some(version_id) = version_id {
+            *self.version_id.entry(version_id.to_owned()).or_insert(0) += delta;
+        }
+        self.total += delta;
     }
 }
 
-fn convert_sql_value_to_json(
-    value: &sqlparser::ast::Value,
+/// True when `insert` carries an `ON CONFLICT ... DO NOTHING` clause.
+/// `detect_conflict_merge_mode` can't be reused here: it also returns `None`
+/// for `DO NOTHING`, which is indistinguishable from "no conflict clause at
+/// all" for its purposes but needs its own row-counting treatment here (an
+/// insert that resolves to `DO NOTHING` creates no row).
+fn insert_is_on_conflict_do_nothing(insert: &Insert) -> bool {
+    matches!(
+        insert.on.as_ref(),
+        Some(OnInsert::OnConflict(on_conflict)) if matches!(on_conflict.action, OnConflictAction::DoNothing)
+    )
+}
+
+/// Walks `sql`'s statements the same way `validate_mutation_statements` does
+/// and turns each INSERT/DELETE against a state-family table into a
+/// `MutationRowDeltas`, deriving deltas from whether a row actually comes
+/// into or out of existence rather than from the shape of the statement:
+///
+/// - A plain INSERT always creates a row (`+1`).
+/// - An `ON CONFLICT ... DO UPDATE` row only creates a row when
+///   `resolve_upsert_conflict` finds no existing match (`+1`); a match
+///   updates in place and changes no row count.
+/// - An `ON CONFLICT ... DO NOTHING` row only creates a row when no existing
+///   match is found either (`+1`); a match is a no-op.
+/// - A DELETE whose WHERE clause pins an exact identity
+///   (`extract_delete_effect_rows` found an `entity_id`) only decrements
+///   (`-1`) when a matching row is actually found to exist; deleting an
+///   identity that isn't stored removes nothing. A DELETE without an
+///   equality predicate on `entity_id` would require a table scan to size
+///   precisely, so its rows are left untracked rather than guessed at.
+///
+/// UPDATE doesn't change row counts and isn't considered.
+fn compute_mutation_row_deltas(
+    host: &dyn HostCallbacks,
+    sql: &str,
     params: &[Value],
-    param_cursor: &mut usize,
-    parse_json_strings: bool,
-) -> Result<Value, EngineError> {
-    match value {
-        sqlparser::ast::Value::SingleQuotedString(text)
-        | sqlparser::ast::Value::DoubleQuotedString(text)
-        | sqlparser::ast::Value::TripleSingleQuotedString(text)
-        | sqlparser::ast::Value::TripleDoubleQuotedString(text)
-        | sqlparser::ast::Value::EscapedStringLiteral(text)
-        | sqlparser::ast::Value::UnicodeStringLiteral(text)
-        | sqlparser::ast::Value::NationalStringLiteral(text) => {
-            if parse_json_strings {
-                serde_json::from_str::<Value>(text).map_err(|error| {
-                    EngineError::rewrite_validation(format!(
-                        "failed to parse JSON snapshot content: {error}"
-                    ))
-                })
+) -> Result<MutationRowDeltas, EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::rewrite_validation(format!("failed to parse mutation SQL for quotas: {error}"))
+    })?;
+
+    let mut deltas = MutationRowDeltas::default();
+    let mut param_cursor: usize = 0;
+    for statement in &statements {
+        // DO NOTHING, and DO UPDATE without a `snapshot_content` assignment,
+        // carry no merge mode, so `resolve_upsert_conflict` can't see the match.
+        let conflict_without_merge = matches!(statement, Statement::Insert(insert) if insert_is_on_conflict_do_nothing(insert) || (insert.on.is_some() && detect_conflict_merge_mode(insert).is_none()));
+        let mut rows = extract_insert_validation_rows(statement, params, &mut param_cursor)?;
+        for row in &mut rows {
+            let creates_new_row = if conflict_without_merge {
+                lookup_existing_state_row(
+                    host,
+                    row.entity_id.as_deref(),
+                    Some(row.schema_key.as_str()),
+                    row.file_id.as_deref(),
+                    &mut row.version_id,
+                )?
+                .is_none()
             } else {
-                Ok(Value::String(text.clone()))
+                match resolve_upsert_conflict(host, row)? {
+                    Some(resolution) => resolution.outcome == UpsertOutcome::Created,
+                    None => true,
+                }
+            };
+            if creates_new_row {
+                deltas.add(Some(row.schema_key.as_str()), row.version_id.as_deref(), 1);
             }
         }
-        sqlparser::ast::Value::Number(number, _) => {
-            if let Ok(parsed) = number.parse::<i64>() {
-                return Ok(Value::Number(parsed.into()));
-            }
-            if let Ok(parsed) = number.parse::<f64>() {
-                if let Some(json_number) = serde_json::Number::from_f64(parsed) {
-                    return Ok(Value::Number(json_number));
+
+        if let Some(mut effect) = extract_delete_effect_rows(statement) {
+            if effect.entity_id.is_some() {
+                let row_exists = lookup_existing_state_row(
+                    host,
+                    effect.entity_id.as_deref(),
+                    effect.schema_key.as_deref(),
+                    effect.file_id.as_deref(),
+                    &mut effect.version_id,
+                )?
+                .is_some();
+                if row_exists {
+                    deltas.add(effect.schema_key.as_deref(), effect.version_id.as_deref(), -1);
                 }
             }
-            Err(EngineError::rewrite_validation(format!(
-                "unsupported numeric literal in validation mutation: {number}"
-            )))
         }
-        sqlparser::ast::Value::Boolean(boolean) => Ok(Value::Bool(*boolean)),
-        sqlparser::ast::Value::Null => Ok(Value::Null),
-        sqlparser::ast::Value::Placeholder(_) => {
-            let Some(bound) = params.get(*param_cursor) else {
-                return Err(EngineError::rewrite_validation(
-                    "not enough SQL parameters for validation mutation",
-                ));
+    }
+
+    Ok(deltas)
+}
+
+/// A registered row-count cap: `schema_key`/`version_id` limits keyed by the
+/// bucket value, and a single table-wide `total` limit.
+#[derive(Debug, Default)]
+struct QuotaLimits {
+    schema_key: std::collections::HashMap<String, i64>,
+    version_id: std::collections::HashMap<String, i64>,
+    total: Option<i64>,
+}
+
+/// The live row counts a `QuotaTracker` enforces limits against, held behind
+/// a single mutex so a check against these counts and the counter update that
+/// follows it happen under one uninterrupted lock (see `check_and_execute`).
+#[derive(Debug, Default)]
+struct QuotaCounts {
+    schema_key: std::collections::HashMap<String, i64>,
+    version_id: std::collections::HashMap<String, i64>,
+    total: i64,
+}
+
+/// Row-count quotas enforced at write-rewrite time, maintained as counters
+/// updated incrementally by `compute_mutation_row_deltas` rather than
+/// recomputed with full table scans: a `schema_key` limit (e.g. "no more
+/// than N rows for this schema"), a `version_id` limit, and a total limit
+/// across all state rows. Each `set_*_limit` seeds its counter from the rows
+/// the host already has for that bucket, so a limit registered against
+/// pre-existing state is enforced from the first write rather than admitting
+/// another `max_rows` worth of rows.
+#[derive(Default)]
+pub struct QuotaTracker {
+    limits: std::sync::Mutex<QuotaLimits>,
+    counts: std::sync::Mutex<QuotaCounts>,
+}
+
+impl QuotaTracker {
+    pub fn set_schema_key_limit(
+        &self,
+        host: &dyn HostCallbacks,
+        schema_key: &str,
+        max_rows: i64,
+    ) -> Result<(), EngineError> {
+        let existing = count_state_rows_for_quota_seed(host, "schema_key", schema_key)?;
+        self.limits
+            .lock()
+            .unwrap()
+            .schema_key
+            .insert(schema_key.to_owned(), max_rows);
+        self.counts
+            .lock()
+            .unwrap()
+            .schema_key
+            .insert(schema_key.to_owned(), existing);
+        Ok(())
+    }
+
+    pub fn set_version_id_limit(
+        &self,
+        host: &dyn HostCallbacks,
+        version_id: &str,
+        max_rows: i64,
+    ) -> Result<(), EngineError> {
+        let existing = count_state_rows_for_quota_seed(host, "version_id", version_id)?;
+        self.limits
+            .lock()
+            .unwrap()
+            .version_id
+            .insert(version_id.to_owned(), max_rows);
+        self.counts
+            .lock()
+            .unwrap()
+            .version_id
+            .insert(version_id.to_owned(), existing);
+        Ok(())
+    }
+
+    pub fn set_total_limit(&self, host: &dyn HostCallbacks, max_rows: i64) -> Result<(), EngineError> {
+        let existing = count_all_state_rows_for_quota_seed(host)?;
+        self.limits.lock().unwrap().total = Some(max_rows);
+        self.counts.lock().unwrap().total = existing;
+        Ok(())
+    }
+
+    /// Checks `deltas` against every registered limit, then runs `run` (the
+    /// statement's validate/rewrite/execute pipeline) and applies `deltas` to
+    /// the counters only once `run` succeeds — a rejected or failed
+    /// statement leaves the tracker unchanged. The counts lock is held for
+    /// the full check-run-apply window, so two concurrent callers can't both
+    /// pass the projection check against the same pre-update counts and then
+    /// both apply, exceeding the limit.
+    fn check_and_execute<T>(
+        &self,
+        deltas: &MutationRowDeltas,
+        run: impl FnOnce() -> Result<T, EngineError>,
+    ) -> Result<T, EngineError> {
+        let limits = self.limits.lock().unwrap();
+        let mut counts = self.counts.lock().unwrap();
+
+        for (schema_key, delta) in &deltas.schema_key {
+            let Some(limit) = limits.schema_key.get(schema_key) else {
+                continue;
             };
-            *param_cursor += 1;
-            if parse_json_strings {
-                if let Value::String(text) = bound {
-                    if let Ok(parsed) = serde_json::from_str::<Value>(text) {
-                        return Ok(parsed);
-                    }
-                }
+            let projected = counts.schema_key.get(schema_key).copied().unwrap_or(0) + delta;
+            if projected > *limit {
+                return Err(EngineError::quota_exceeded(format!(
+                    "schema_key `{schema_key}` quota exceeded: would have {projected} rows, \
+                     over the limit of {limit} by {}",
+                    projected - limit
+                )));
             }
-            Ok(bound.clone())
         }
-        _ => Err(EngineError::rewrite_validation(format!(
-            "unsupported SQL literal in validation mutation: {value}"
-        ))),
+        for (version_id, delta) in &deltas.version_id {
+            let Some(limit) = limits.version_id.get(version_id) else {
+                continue;
+            };
+            let projected = counts.version_id.get(version_id).copied().unwrap_or(0) + delta;
+            if projected > *limit {
+                return Err(EngineError::quota_exceeded(format!(
+                    "version_id `{version_id}` quota exceeded: would have {projected} rows, \
+                     over the limit of {limit} by {}",
+                    projected - limit
+                )));
+            }
+        }
+        if let Some(limit) = limits.total {
+            let projected = counts.total + deltas.total;
+            if projected > limit {
+                return Err(EngineError::quota_exceeded(format!(
+                    "total state row quota exceeded: would have {projected} rows, over the \
+                     limit of {limit} by {}",
+                    projected - limit
+                )));
+            }
+        }
+        drop(limits);
+
+        let result = run()?;
+
+        for (schema_key, delta) in &deltas.schema_key {
+            *counts.schema_key.entry(schema_key.clone()).or_insert(0) += delta;
+        }
+        for (version_id, delta) in &deltas.version_id {
+            *counts.version_id.entry(version_id.clone()).or_insert(0) += delta;
+        }
+        counts.total += deltas.total;
+
+        Ok(result)
     }
 }
 
-fn validate_single_mutation_row(
+/// Seeds a `schema_key`/`version_id` quota counter by asking the host how
+/// many state rows already carry that bucket value, so registering a limit
+/// against a schema/version that already has rows enforces correctly from
+/// the first write rather than starting every fresh process back at zero.
+fn count_state_rows_for_quota_seed(
     host: &dyn HostCallbacks,
-    row: &MutationValidationRow,
-) -> Result<(), EngineError> {
-    let schema = fetch_stored_schema(host, &row.schema_key, &row.schema_version)?;
-    validate_cel_expressions_in_schema(&schema)?;
-    let compiled = JSONSchema::compile(&schema).map_err(|error| {
-        EngineError::rewrite_validation(format!(
-            "failed to compile schema {}@{}: {error}",
-            row.schema_key, row.schema_version
-        ))
-    })?;
-    if let Err(mut errors) = compiled.validate(&row.snapshot_content) {
-        let detail = errors
-            .next()
-            .map(|error| error.to_string())
-            .unwrap_or_else(|| "unknown validation failure".to_owned());
-        return Err(EngineError::rewrite_validation(format!(
-            "snapshot for {}@{} failed JSON Schema validation: {detail}",
-            row.schema_key, row.schema_version
-        )));
-    }
-    Ok(())
+    column: &str,
+    value: &str,
+) -> Result<i64, EngineError> {
+    let sql = format!("SELECT COUNT(*) AS count FROM {STATE_BY_VERSION} WHERE {column} = ?");
+    let response = host
+        .execute(HostExecuteRequest {
+            request_id: "rust-quota-seed-count".to_owned(),
+            sql,
+            params: vec![Value::String(value.to_owned())],
+            statement_kind: RUST_KIND_PASSTHROUGH,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_QUOTA_EXCEEDED))?;
+    Ok(extract_quota_seed_count(&response))
 }
 
-fn fetch_stored_schema(
-    host: &dyn HostCallbacks,
-    schema_key: &str,
-    schema_version: &str,
-) -> Result<Value, EngineError> {
-    let sql = "SELECT value FROM stored_schema \
-               WHERE json_extract(value, '$.\"x-lix-key\"') = ? \
-               AND json_extract(value, '$.\"x-lix-version\"') = ? \
-               ORDER BY rowid DESC LIMIT 1";
+/// Same seeding as `count_state_rows_for_quota_seed`, but for the table-wide
+/// total limit.
+fn count_all_state_rows_for_quota_seed(host: &dyn HostCallbacks) -> Result<i64, EngineError> {
     let response = host
         .execute(HostExecuteRequest {
-            request_id: "rust-validation-schema-load".to_owned(),
-            sql: sql.to_owned(),
-            params: vec![
-                Value::String(schema_key.to_owned()),
-                Value::String(schema_version.to_owned()),
-            ],
+            request_id: "rust-quota-seed-count".to_owned(),
+            sql: format!("SELECT COUNT(*) AS count FROM {STATE_BY_VERSION}"),
+            params: vec![],
             statement_kind: RUST_KIND_PASSTHROUGH,
         })
-        .map_err(|error| map_host_error(error, LIX_RUST_REWRITE_VALIDATION))?;
+        .map_err(|error| map_host_error(error, LIX_RUST_QUOTA_EXCEEDED))?;
+    Ok(extract_quota_seed_count(&response))
+}
 
-    let Some(first_row) = response.rows.first() else {
-        return Err(EngineError::rewrite_validation(format!(
-            "schema {}@{} is not stored",
-            schema_key, schema_version
-        )));
+fn extract_quota_seed_count(response: &HostExecuteResponse) -> i64 {
+    response
+        .rows
+        .first()
+        .and_then(|row| row.get("count"))
+        .and_then(Value::as_i64)
+        .unwrap_or(0)
+}
+
+/// Same pipeline as `execute_with_host`, but enforces `quotas`'s row-count
+/// caps on a WRITE_REWRITE or VALIDATION insert: computes the projected
+/// post-mutation `(schema_key)`/`(version_id)`/total counts from the
+/// statement's `__lix_mutation_rows` shape and rejects with
+/// `LIX_RUST_QUOTA_EXCEEDED` before the statement reaches `host.execute` if
+/// any registered limit would be exceeded. The validate/rewrite/execute
+/// pipeline runs inside `quotas.check_and_execute`, so the counters are only
+/// ever incremented once that pipeline succeeds — a validation failure or a
+/// failed `host.execute` leaves the tracker exactly as it was. Deletes
+/// matched to an exact identity decrement the same counters, so limits stay
+/// accurate without re-scanning state.
+pub fn execute_with_host_and_quotas(
+    quotas: &QuotaTracker,
+    host: &dyn HostCallbacks,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let plan = plan_execute(&request.sql);
+    let statement_kind = plan.statement_kind;
+
+    if statement_kind == RUST_KIND_VALIDATION {
+        validate_validation_mutations(&request.sql)?;
+    }
+
+    let should_detect_changes =
+        should_run_plugin_change_detection(statement_kind, &request.sql, request.params.as_slice())?;
+
+    let validate_rewrite_and_execute = || -> Result<(MutationValidationOutcome, HostExecuteResponse), EngineError> {
+        let validation_outcome = validate_state_mutation_rows(
+            host,
+            &request.sql,
+            request.params.as_slice(),
+            statement_kind,
+        )?;
+
+        let rewritten_sql = rewrite_sql_for_execution_with_schema_defaults(
+            host,
+            &request.sql,
+            statement_kind,
+            request.params.as_slice(),
+            request.as_of.as_ref(),
+        )?;
+
+        let execute_response = host
+            .execute(HostExecuteRequest {
+                request_id: request.request_id.clone(),
+                sql: rewritten_sql,
+                params: request.params.clone(),
+                statement_kind,
+            })
+            .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
+
+        Ok((validation_outcome, execute_response))
     };
 
-    match first_row {
-        Value::Object(record) => {
-            let Some(value) = record.get("value") else {
-                return Err(EngineError::rewrite_validation(
-                    "stored_schema row missing 'value' column",
-                ));
-            };
-            if let Value::String(text) = value {
-                serde_json::from_str::<Value>(text).map_err(|error| {
-                    EngineError::rewrite_validation(format!(
-                        "stored schema payload is not valid JSON: {error}"
-                    ))
-                })
-            } else {
-                Ok(value.clone())
-            }
-        }
-        Value::String(text) => serde_json::from_str::<Value>(text).map_err(|error| {
-            EngineError::rewrite_validation(format!(
-                "stored schema payload is not valid JSON: {error}"
-            ))
-        }),
-        _ => Err(EngineError::rewrite_validation(
-            "stored schema query returned an unsupported row shape",
-        )),
+    let (validation_outcome, execute_response) =
+        if statement_kind == RUST_KIND_WRITE_REWRITE || statement_kind == RUST_KIND_VALIDATION {
+            let deltas = compute_mutation_row_deltas(host, &request.sql, request.params.as_slice())?;
+            quotas.check_and_execute(&deltas, validate_rewrite_and_execute)?
+        } else {
+            validate_rewrite_and_execute()?
+        };
+
+    let plugin_changes = if should_detect_changes {
+        execute_plugin_change_detection(
+            host,
+            &request.request_id,
+            request.plugin_change_requests.as_slice(),
+        )?
+    } else {
+        Vec::new()
+    };
+
+    let rows_affected = if plan.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+        execute_response.rows.len() as i64
+    } else {
+        execute_response.rows_affected
+    };
+
+    Ok(ExecuteResult {
+        statement_kind,
+        rows: execute_response.rows,
+        rows_affected,
+        last_insert_row_id: execute_response.last_insert_row_id,
+        plugin_changes,
+        upsert_resolutions: validation_outcome.upsert_resolutions,
+        transaction_report: validation_outcome.transaction_report,
+        rewritten_sql: None,
+    })
+}
+
+pub fn route_statement_kind(sql: &str) -> &'static str {
+    let dialect = SQLiteDialect {};
+    let parsed = Parser::parse_sql(&dialect, sql);
+
+    let statements = match parsed {
+        Ok(value) if !value.is_empty() => value,
+        _ => return RUST_KIND_PASSTHROUGH,
+    };
+
+    classify_statements(&statements, sql)
+}
+
+/// Same classification as `route_statement_kind`, but parses through `cache`
+/// so a repeated SQL shape pays the `Parser::parse_sql` cost once.
+pub fn route_statement_kind_cached(cache: &ParseCache, sql: &str) -> &'static str {
+    match parse_cached(cache, sql) {
+        Ok(statements) if !statements.is_empty() => classify_statements(&statements, sql),
+        _ => RUST_KIND_PASSTHROUGH,
     }
 }
 
-fn validate_cel_expressions_in_schema(schema: &Value) -> Result<(), EngineError> {
-    match schema {
-        Value::Object(record) => {
-            if let Some(Value::String(expression)) = record.get("x-lix-default") {
-                Program::compile(expression).map_err(|error| {
-                    EngineError::rewrite_validation(format!(
-                        "invalid CEL expression in x-lix-default: {error}"
-                    ))
-                })?;
+fn classify_statements(statements: &[Statement], sql: &str) -> &'static str {
+    let mut saw_read = false;
+    let mut saw_write = false;
+
+    for statement in statements {
+        match statement {
+            Statement::Query(_) => {
+                saw_read = true;
             }
-            if let Some(Value::Object(overrides)) = record.get("x-lix-override-lixcols") {
-                for (key, value) in overrides {
-                    if let Value::String(expression) = value {
-                        Program::compile(expression).map_err(|error| {
-                            EngineError::rewrite_validation(format!(
-                                "invalid CEL expression in x-lix-override-lixcols.{key}: {error}"
-                            ))
-                        })?;
-                    }
-                }
+            Statement::Insert(_) => {
+                saw_write = true;
             }
-            for value in record.values() {
-                validate_cel_expressions_in_schema(value)?;
+            Statement::Update { .. } => {
+                saw_write = true;
             }
-            Ok(())
-        }
-        Value::Array(values) => {
-            for value in values {
-                validate_cel_expressions_in_schema(value)?;
+            Statement::Delete(_) => {
+                saw_write = true;
+            }
+            _ => {
+                return RUST_KIND_PASSTHROUGH;
             }
-            Ok(())
         }
-        _ => Ok(()),
     }
-}
 
-fn rewrite_statement_for_write_rewrite(
-    statement: &Statement,
-) -> Result<(String, bool), EngineError> {
-    let rewritten = match statement {
-        Statement::Insert(insert) => rewrite_insert_for_write_rewrite(insert)?,
-        Statement::Update {
-            table,
-            assignments,
-            from,
-            selection,
-            returning,
-            ..
-        } => rewrite_update_for_write_rewrite(
-            table,
-            assignments.as_slice(),
-            from,
-            selection,
-            returning,
-        ),
-        Statement::Delete(delete) => rewrite_delete_for_write_rewrite(delete),
-        _ => None,
-    };
+    if saw_write {
+        if is_validation_sql(sql) {
+            return RUST_KIND_VALIDATION;
+        }
+        return RUST_KIND_WRITE_REWRITE;
+    }
 
-    if let Some(sql) = rewritten {
-        Ok((sql, true))
-    } else {
-        Ok((statement.to_string(), false))
+    if saw_read {
+        return RUST_KIND_READ_REWRITE;
     }
+
+    RUST_KIND_PASSTHROUGH
 }
 
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum WriteTarget {
-    State,
-    StateAll,
-    StateByVersion,
-    StateVtable,
-    Other,
+pub fn plan_execute(sql: &str) -> ExecutePlan {
+    build_execute_plan(route_statement_kind(sql), sql_has_returning_clause(sql))
 }
 
-fn classify_write_target(name: &ObjectName) -> WriteTarget {
-    let Some(last) = name.0.last() else {
-        return WriteTarget::Other;
+/// Same planning as `plan_execute`, but routes through `cache` so the parse
+/// cost of `route_statement_kind_cached` is paid at most once per normalized
+/// SQL shape.
+pub fn plan_execute_cached(cache: &ParseCache, sql: &str) -> ExecutePlan {
+    let statement_kind = route_statement_kind_cached(cache, sql);
+    let has_returning = parse_cached(cache, sql)
+        .map(|statements| statement_has_returning(&statements))
+        .unwrap_or(false);
+    build_execute_plan(statement_kind, has_returning)
+}
+
+fn build_execute_plan(statement_kind: &'static str, has_returning: bool) -> ExecutePlan {
+    let preprocess_mode = if statement_kind == RUST_KIND_PASSTHROUGH {
+        "none"
+    } else {
+        "full"
     };
-    let value = last.value.as_str();
-    if value.eq_ignore_ascii_case(STATE_VIEW) {
-        return WriteTarget::State;
-    }
-    if value.eq_ignore_ascii_case(STATE_ALL_VIEW) {
-        return WriteTarget::StateAll;
-    }
-    if value.eq_ignore_ascii_case(STATE_BY_VERSION) {
-        return WriteTarget::StateByVersion;
-    }
-    if value.eq_ignore_ascii_case(INTERNAL_STATE_VTABLE) {
-        return WriteTarget::StateVtable;
+    let rows_affected_mode = if statement_kind == RUST_KIND_READ_REWRITE
+        || statement_kind == RUST_KIND_PASSTHROUGH
+        || has_returning
+    {
+        RUST_ROWS_AFFECTED_ROWS_LENGTH
+    } else {
+        RUST_ROWS_AFFECTED_SQLITE_CHANGES
+    };
+    ExecutePlan {
+        statement_kind,
+        preprocess_mode,
+        rows_affected_mode,
     }
-    WriteTarget::Other
 }
 
-fn resolve_physical_target(target: WriteTarget) -> Option<&'static str> {
-    match target {
-        WriteTarget::State | WriteTarget::StateAll | WriteTarget::StateByVersion => {
-            Some(STATE_BY_VERSION)
-        }
-        WriteTarget::StateVtable => Some(INTERNAL_STATE_VTABLE),
-        WriteTarget::Other => None,
+/// Reports whether any statement in `sql` carries a `RETURNING` clause, so
+/// `plan_execute` can route `rows_affected_mode` to
+/// `RUST_ROWS_AFFECTED_ROWS_LENGTH` even for an otherwise write-shaped
+/// statement. A parse failure is treated as "no RETURNING" the same way
+/// `route_statement_kind` falls back to `RUST_KIND_PASSTHROUGH`.
+fn sql_has_returning_clause(sql: &str) -> bool {
+    let dialect = SQLiteDialect {};
+    match Parser::parse_sql(&dialect, sql) {
+        Ok(statements) => statement_has_returning(&statements),
+        Err(_) => false,
     }
 }
 
-fn rewrite_insert_for_write_rewrite(insert: &Insert) -> Result<Option<String>, EngineError> {
-    if insert.on.is_some()
-        || insert.returning.is_some()
-        || insert.partitioned.is_some()
-        || !insert.after_columns.is_empty()
-        || insert.table_alias.is_some()
-    {
-        return Ok(None);
+fn statement_has_returning(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match statement {
+        Statement::Insert(insert) => insert.returning.is_some(),
+        Statement::Update { returning, .. } => returning.is_some(),
+        Statement::Delete(delete) => delete.returning.is_some(),
+        _ => false,
+    })
+}
+
+/// Distinguishes whether an `ExecutePlan` surfaces rows actually returned (a
+/// `SELECT`, or a write with a `RETURNING` clause) versus a bare affected-row
+/// count, mirroring toydb's separate statement-outcome variants. This is the
+/// Rust-side equivalent of `ExecutePlan::rows_affected_mode`, which stays a
+/// stable string for the FFI boundary.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StatementResult {
+    RowsAffected,
+    RowsReturned,
+}
+
+impl ExecutePlan {
+    pub fn result_shape(&self) -> StatementResult {
+        if self.rows_affected_mode == RUST_ROWS_AFFECTED_ROWS_LENGTH {
+            StatementResult::RowsReturned
+        } else {
+            StatementResult::RowsAffected
+        }
     }
+}
 
-    let target_kind = classify_write_target(&insert.table_name);
-    let Some(target_table) = resolve_physical_target(target_kind) else {
-        return Ok(None);
-    };
+fn is_validation_sql(sql: &str) -> bool {
+    let lowered = sql.to_lowercase();
+    lowered.contains("insert into state")
+        || lowered.contains("insert into state_all")
+        || lowered.contains("update state")
+        || lowered.contains("update state_all")
+        || lowered.contains("delete from state")
+        || lowered.contains("delete from state_all")
+}
 
-    let Some(source) = &insert.source else {
-        return Ok(None);
-    };
+pub fn rewrite_sql_for_execution(sql: &str, statement_kind: &str) -> Result<String, EngineError> {
+    rewrite_sql_for_execution_as_of(sql, statement_kind, None)
+}
 
-    let SetExpr::Values(values) = &*source.body else {
-        return Ok(None);
-    };
+/// Same rewriting as `rewrite_sql_for_execution`, but parses through `cache`
+/// so a repeated SQL shape pays the parse cost once.
+pub fn rewrite_sql_for_execution_cached(
+    cache: &ParseCache,
+    sql: &str,
+    statement_kind: &str,
+) -> Result<String, EngineError> {
+    rewrite_sql_for_execution_as_of_cached(cache, sql, statement_kind, None)
+}
 
-    if insert.columns.is_empty() {
-        return Ok(None);
+/// Same rewriting as `rewrite_sql_for_execution`, but when `statement_kind`
+/// is `RUST_KIND_READ_REWRITE` and `as_of` is present, `lix_internal_state_vtable`
+/// is rewritten against historical state (a past commit or an RFC3339
+/// timestamp) instead of the live untracked view.
+pub fn rewrite_sql_for_execution_as_of(
+    sql: &str,
+    statement_kind: &str,
+    as_of: Option<&AsOf>,
+) -> Result<String, EngineError> {
+    if statement_kind == RUST_KIND_PASSTHROUGH {
+        return Ok(sql.to_owned());
     }
 
-    let mut materialized_columns: Vec<String> = insert
-        .columns
-        .iter()
-        .map(|column| column.value.clone())
-        .collect();
-    let needs_active_version = target_kind == WriteTarget::State
-        && !materialized_columns
-            .iter()
-            .any(|column| column.eq_ignore_ascii_case("version_id"));
-    if needs_active_version {
-        materialized_columns.push("version_id".to_owned());
-    }
+    let dialect = SQLiteDialect {};
+    let parsed = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse SQL for rewrite: {error}"))
+    })?;
 
-    let mut rendered_rows: Vec<String> = Vec::with_capacity(values.rows.len());
-    for row in &values.rows {
-        if row.len() != insert.columns.len() {
-            return Err(EngineError::protocol_mismatch(
-                "insert row shape does not match declared columns",
-            ));
-        }
+    rewrite_statements_for_execution(&parsed, sql, statement_kind, as_of)
+}
 
-        let mut rendered_exprs: Vec<String> = row.iter().map(ToString::to_string).collect();
-        if needs_active_version {
-            rendered_exprs.push("(SELECT version_id FROM active_version)".to_owned());
-        }
-        rendered_rows.push(format!("({})", rendered_exprs.join(", ")));
+/// Same rewriting as `rewrite_sql_for_execution_as_of`, but parses through
+/// `cache` so a repeated SQL shape pays the parse cost once.
+pub fn rewrite_sql_for_execution_as_of_cached(
+    cache: &ParseCache,
+    sql: &str,
+    statement_kind: &str,
+    as_of: Option<&AsOf>,
+) -> Result<String, EngineError> {
+    if statement_kind == RUST_KIND_PASSTHROUGH {
+        return Ok(sql.to_owned());
     }
 
-    let materialized_columns_sql = materialized_columns
-        .iter()
-        .map(|column| quote_ident(column))
-        .collect::<Vec<String>>()
-        .join(", ");
+    let parsed = parse_cached(cache, sql)?;
+    rewrite_statements_for_execution(&parsed, sql, statement_kind, as_of)
+}
 
-    let sql = format!(
-        "WITH \"{MUTATION_ROW_CTE}\" ({materialized_columns_sql}) AS (VALUES {}) \
-         INSERT INTO {target_table} ({materialized_columns_sql}) \
-         SELECT {materialized_columns_sql} FROM \"{MUTATION_ROW_CTE}\"",
-        rendered_rows.join(", ")
-    );
+fn rewrite_statements_for_execution(
+    parsed: &[Statement],
+    sql: &str,
+    statement_kind: &str,
+    as_of: Option<&AsOf>,
+) -> Result<String, EngineError> {
+    if parsed.is_empty() {
+        return Err(EngineError::protocol_mismatch(
+            "expected at least one statement for rewrite",
+        ));
+    }
 
-    Ok(Some(sql))
+    let mut rewritten_statements: Vec<String> = Vec::with_capacity(parsed.len());
+    let mut changed = false;
+    for statement in parsed {
+        let (rewritten, statement_changed) = match statement_kind {
+            RUST_KIND_READ_REWRITE => {
+                let mut statement_clone = statement.clone();
+                let statement_changed =
+                    rewrite_statement_for_read_rewrite(&mut statement_clone, as_of)?;
+                (statement_clone.to_string(), statement_changed)
+            }
+            RUST_KIND_WRITE_REWRITE | RUST_KIND_VALIDATION => {
+                rewrite_statement_for_write_rewrite(statement)?
+            }
+            _ => (statement.to_string(), false),
+        };
+        rewritten_statements.push(rewritten);
+        changed |= statement_changed;
+    }
+
+    if !changed {
+        return Ok(sql.to_owned());
+    }
+
+    Ok(rewritten_statements.join("; "))
 }
 
-fn rewrite_update_for_write_rewrite(
-    table: &TableWithJoins,
-    assignments: &[sqlparser::ast::Assignment],
-    from: &Option<TableWithJoins>,
-    selection: &Option<sqlparser::ast::Expr>,
-    returning: &Option<Vec<sqlparser::ast::SelectItem>>,
-) -> Option<String> {
-    if table.joins.len() > 0 || from.is_some() || returning.is_some() {
-        return None;
+/// Same rewriting as `rewrite_sql_for_execution_as_of`, but for
+/// `WRITE_REWRITE`/`VALIDATION` statements, first resolves each INSERT row's
+/// `x-lix-default`/`x-lix-override-lixcols` CEL expressions against its
+/// stored schema via `materialize_cel_row_values` and bakes the results into
+/// the rewritten `__lix_mutation_rows` CTE, so the physical insert never sees
+/// the caller's unmaterialized row. Used by `execute_with_host` and
+/// `execute_with_host_and_quotas`; the `_cached`/`_and_plan_cache`/
+/// `_and_prepared_cache` execution paths rewrite without it and skip
+/// materialization.
+fn rewrite_sql_for_execution_with_schema_defaults(
+    host: &dyn HostCallbacks,
+    sql: &str,
+    statement_kind: &str,
+    params: &[Value],
+    as_of: Option<&AsOf>,
+) -> Result<String, EngineError> {
+    if statement_kind != RUST_KIND_WRITE_REWRITE && statement_kind != RUST_KIND_VALIDATION {
+        return rewrite_sql_for_execution_as_of(sql, statement_kind, as_of);
     }
-    let TableFactor::Table {
-        name, alias, args, ..
-    } = &table.relation
-    else {
-        return None;
-    };
 
-    if alias.is_some() || args.is_some() {
-        return None;
+    let dialect = SQLiteDialect {};
+    let parsed = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse SQL for rewrite: {error}"))
+    })?;
+    if parsed.is_empty() {
+        return Err(EngineError::protocol_mismatch(
+            "expected at least one statement for rewrite",
+        ));
     }
 
-    let target_kind = classify_write_target(name);
-    let target_table = resolve_physical_target(target_kind)?;
+    let mut rewritten_statements: Vec<String> = Vec::with_capacity(parsed.len());
+    let mut changed = false;
+    for statement in &parsed {
+        let (rewritten, statement_changed) = match statement {
+            Statement::Insert(insert) => {
+                let materializations = materialize_cel_row_values(host, insert, params)?;
+                let materializations = apply_upsert_merge_to_materializations(
+                    host,
+                    statement,
+                    params,
+                    materializations,
+                )?;
+                match rewrite_insert_for_write_rewrite_with_materializations(
+                    insert,
+                    materializations,
+                )? {
+                    Some(sql) => (sql, true),
+                    None => (statement.to_string(), false),
+                }
+            }
+            _ => rewrite_statement_for_write_rewrite(statement)?,
+        };
+        rewritten_statements.push(rewritten);
+        changed |= statement_changed;
+    }
 
-    let predicate = combine_write_predicate(selection, target_kind);
-    let assignments_sql = assignments
-        .iter()
-        .map(ToString::to_string)
-        .collect::<Vec<String>>()
-        .join(", ");
+    if !changed {
+        return Ok(sql.to_owned());
+    }
 
-    let key_columns_sql = STATE_MUTATION_KEY_COLUMNS.join(", ");
-    let where_clause = match predicate {
-        Some(predicate_sql) => format!(" WHERE {predicate_sql}"),
-        None => String::new(),
+    Ok(rewritten_statements.join("; "))
+}
+
+/// Resolves each row's upsert conflict merge exactly as `resolve_upsert_conflict`
+/// does during validation, then folds the merged `snapshot_content` into
+/// `materializations` so the rewritten INSERT persists precisely the row that
+/// was validated, instead of letting the `ON CONFLICT DO UPDATE` assignment
+/// re-run the caller's merge expression against an already-merged value (see
+/// `render_do_update_assignment`). This re-fetches the conflicting row from
+/// the host independently of validation's own fetch, matching the existing
+/// pattern where the rewrite pass and the validation pass each re-derive
+/// state from the host rather than sharing it. Leaves `materializations`
+/// untouched when `statement` has no merging (`ShallowMerge`/`DeepMerge`)
+/// conflict mode.
+fn apply_upsert_merge_to_materializations(
+    host: &dyn HostCallbacks,
+    statement: &Statement,
+    params: &[Value],
+    materializations: Option<Vec<RowCelMaterialization>>,
+) -> Result<Option<Vec<RowCelMaterialization>>, EngineError> {
+    let Statement::Insert(insert) = statement else {
+        return Ok(materializations);
     };
+    if !matches!(
+        detect_conflict_merge_mode(insert),
+        Some(ConflictMergeMode::ShallowMerge) | Some(ConflictMergeMode::DeepMerge)
+    ) {
+        return Ok(materializations);
+    }
 
-    Some(format!(
-        "WITH \"{MUTATION_ROW_CTE}\" AS (\
-            SELECT {key_columns_sql} \
-            FROM {target_table}{where_clause} \
-            ORDER BY {key_columns_sql}\
-        ) \
-        UPDATE {target_table} \
-        SET {assignments_sql} \
-        WHERE ({key_columns_sql}) IN (\
-            SELECT {key_columns_sql} FROM \"{MUTATION_ROW_CTE}\"\
-        )"
-    ))
+    let mut param_cursor = 0;
+    let mut rows = extract_insert_validation_rows(statement, params, &mut param_cursor)?;
+
+    let mut materializations = materializations
+        .unwrap_or_else(|| (0..rows.len()).map(|_| RowCelMaterialization::default()).collect());
+    for (materialization, row) in materializations.iter_mut().zip(rows.iter_mut()) {
+        if matches!(
+            row.conflict_merge_mode,
+            Some(ConflictMergeMode::ShallowMerge) | Some(ConflictMergeMode::DeepMerge)
+        ) {
+            resolve_upsert_conflict(host, row)?;
+            materialization.snapshot_content = Some(row.snapshot_content.clone());
+        }
+    }
+    Ok(Some(materializations))
 }
 
-fn rewrite_delete_for_write_rewrite(delete: &Delete) -> Option<String> {
-    if !delete.tables.is_empty()
-        || delete.using.is_some()
-        || delete.returning.is_some()
-        || !delete.order_by.is_empty()
-        || delete.limit.is_some()
-    {
-        return None;
+fn rewrite_statement_for_read_rewrite(
+    statement: &mut Statement,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    match statement {
+        Statement::Query(query) => rewrite_query_for_read_rewrite(query, as_of),
+        _ => Ok(false),
     }
+}
 
-    let tables = match &delete.from {
-        FromTable::WithFromKeyword(value) => value,
-        FromTable::WithoutKeyword(value) => value,
-    };
-    if tables.len() != 1 {
-        return None;
+fn rewrite_query_for_read_rewrite(
+    query: &mut Query,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    let mut changed = false;
+
+    if let Some(with_clause) = &mut query.with {
+        for cte in &mut with_clause.cte_tables {
+            changed |= rewrite_query_for_read_rewrite(&mut cte.query, as_of)?;
+        }
     }
 
-    let table_with_joins = tables.first()?;
-    if !table_with_joins.joins.is_empty() {
-        return None;
+    changed |= rewrite_set_expr_for_read_rewrite(&mut query.body, as_of)?;
+    Ok(changed)
+}
+
+fn rewrite_set_expr_for_read_rewrite(
+    set_expr: &mut SetExpr,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    match set_expr {
+        SetExpr::Select(select) => rewrite_select_for_read_rewrite(select, as_of),
+        SetExpr::Query(query) => rewrite_query_for_read_rewrite(query, as_of),
+        SetExpr::SetOperation { left, right, .. } => {
+            let left_changed = rewrite_set_expr_for_read_rewrite(left, as_of)?;
+            let right_changed = rewrite_set_expr_for_read_rewrite(right, as_of)?;
+            Ok(left_changed || right_changed)
+        }
+        _ => Ok(false),
     }
+}
 
-    let TableFactor::Table {
-        name, alias, args, ..
-    } = &table_with_joins.relation
-    else {
-        return None;
-    };
-    if alias.is_some() || args.is_some() {
-        return None;
+fn rewrite_select_for_read_rewrite(
+    select: &mut Select,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    let mut changed = false;
+    for table_with_joins in &mut select.from {
+        changed |= rewrite_table_with_joins_for_read_rewrite(table_with_joins, as_of)?;
     }
+    Ok(changed)
+}
 
-    let target_kind = classify_write_target(name);
-    let target_table = resolve_physical_target(target_kind)?;
-    let predicate = combine_write_predicate(&delete.selection, target_kind);
-    let key_columns_sql = STATE_MUTATION_KEY_COLUMNS.join(", ");
-    let where_clause = match predicate {
-        Some(predicate_sql) => format!(" WHERE {predicate_sql}"),
-        None => String::new(),
-    };
+fn rewrite_table_with_joins_for_read_rewrite(
+    table_with_joins: &mut TableWithJoins,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    let mut changed =
+        rewrite_table_factor_for_read_rewrite(&mut table_with_joins.relation, as_of)?;
 
-    Some(format!(
-        "WITH \"{MUTATION_ROW_CTE}\" AS (\
-            SELECT {key_columns_sql} \
-            FROM {target_table}{where_clause} \
-            ORDER BY {key_columns_sql}\
-        ) \
-        DELETE FROM {target_table} \
-        WHERE ({key_columns_sql}) IN (\
-            SELECT {key_columns_sql} FROM \"{MUTATION_ROW_CTE}\"\
-        )"
-    ))
+    for join in &mut table_with_joins.joins {
+        changed |= rewrite_table_factor_for_read_rewrite(&mut join.relation, as_of)?;
+    }
+
+    Ok(changed)
 }
 
-fn combine_write_predicate(
-    selection: &Option<sqlparser::ast::Expr>,
-    target: WriteTarget,
-) -> Option<String> {
-    let active_version_filter = "version_id IN (SELECT version_id FROM active_version)";
+fn rewrite_table_factor_for_read_rewrite(
+    table_factor: &mut TableFactor,
+    as_of: Option<&AsOf>,
+) -> Result<bool, EngineError> {
+    match table_factor {
+        TableFactor::Table {
+            name, alias, args, ..
+        } => {
+            if args.is_some() || !is_target_vtable_name(name) {
+                return Ok(false);
+            }
 
-    let selection_sql = selection.as_ref().map(ToString::to_string);
+            let subquery = build_state_vtable_equivalent_subquery(as_of)?;
+            let derived_alias = alias.take().unwrap_or_else(|| TableAlias {
+                name: Ident::new(INTERNAL_STATE_VTABLE),
+                columns: Vec::new(),
+            });
+            *table_factor = TableFactor::Derived {
+                lateral: false,
+                subquery: Box::new(subquery),
+                alias: Some(derived_alias),
+            };
+            Ok(true)
+        }
+        TableFactor::Derived { subquery, .. } => rewrite_query_for_read_rewrite(subquery, as_of),
+        TableFactor::NestedJoin {
+            table_with_joins, ..
+        } => rewrite_table_with_joins_for_read_rewrite(table_with_joins, as_of),
+        TableFactor::Pivot { table, .. } => rewrite_table_factor_for_read_rewrite(table, as_of),
+        TableFactor::Unpivot { table, .. } => rewrite_table_factor_for_read_rewrite(table, as_of),
+        TableFactor::MatchRecognize { table, .. } => {
+            rewrite_table_factor_for_read_rewrite(table, as_of)
+        }
+        _ => Ok(false),
+    }
+}
+
+fn is_target_vtable_name(name: &ObjectName) -> bool {
+    name.0
+        .last()
+        .map(|part| part.value.eq_ignore_ascii_case(INTERNAL_STATE_VTABLE))
+        .unwrap_or(false)
+}
+
+fn build_state_vtable_equivalent_subquery(as_of: Option<&AsOf>) -> Result<Query, EngineError> {
+    let sql = match as_of {
+        None => "SELECT \
+                entity_id, \
+                schema_key, \
+                file_id, \
+                version_id, \
+                plugin_key, \
+                snapshot_content, \
+                schema_version, \
+                created_at, \
+                updated_at, \
+                inherited_from_version_id, \
+                NULL AS change_id, \
+                1 AS untracked, \
+                NULL AS commit_id, \
+                NULL AS writer_key, \
+                NULL AS metadata \
+            FROM lix_internal_state_all_untracked"
+            .to_owned(),
+        Some(AsOf::Timestamp(timestamp)) => format!(
+            "SELECT \
+                c.entity_id, \
+                c.schema_key, \
+                c.file_id, \
+                c.version_id, \
+                c.plugin_key, \
+                c.snapshot_content, \
+                c.schema_version, \
+                c.created_at, \
+                c.created_at AS updated_at, \
+                NULL AS inherited_from_version_id, \
+                c.id AS change_id, \
+                0 AS untracked, \
+                c.commit_id AS commit_id, \
+                c.writer_key AS writer_key, \
+                c.metadata AS metadata \
+            FROM lix_internal_change AS c \
+            JOIN ( \
+                SELECT entity_id, schema_key, file_id, version_id, MAX(rowid) AS rowid \
+                FROM lix_internal_change \
+                WHERE created_at <= '{escaped}' \
+                GROUP BY entity_id, schema_key, file_id, version_id \
+            ) AS latest ON latest.rowid = c.rowid \
+            WHERE c.created_at <= '{escaped}'",
+            escaped = escape_sql_string_literal(timestamp)
+        ),
+        Some(AsOf::CommitId(commit_id)) => format!(
+            "WITH RECURSIVE reachable_commit(id) AS ( \
+                SELECT id FROM lix_internal_commit WHERE id = '{escaped}' \
+                UNION \
+                SELECT parent.parent_commit_id \
+                FROM lix_internal_commit AS parent \
+                JOIN reachable_commit ON reachable_commit.id = parent.id \
+                WHERE parent.parent_commit_id IS NOT NULL \
+            ) \
+            SELECT \
+                c.entity_id, \
+                c.schema_key, \
+                c.file_id, \
+                c.version_id, \
+                c.plugin_key, \
+                c.snapshot_content, \
+                c.schema_version, \
+                c.created_at, \
+                c.created_at AS updated_at, \
+                NULL AS inherited_from_version_id, \
+                c.id AS change_id, \
+                0 AS untracked, \
+                c.commit_id AS commit_id, \
+                c.writer_key AS writer_key, \
+                c.metadata AS metadata \
+            FROM lix_internal_change AS c \
+            JOIN ( \
+                SELECT entity_id, schema_key, file_id, version_id, MAX(rowid) AS rowid \
+                FROM lix_internal_change \
+                WHERE commit_id IN (SELECT id FROM reachable_commit) \
+                GROUP BY entity_id, schema_key, file_id, version_id \
+            ) AS latest ON latest.rowid = c.rowid \
+            WHERE c.commit_id IN (SELECT id FROM reachable_commit)",
+            escaped = escape_sql_string_literal(commit_id)
+        ),
+    };
+
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, &sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!(
+            "failed to construct read rewrite for {INTERNAL_STATE_VTABLE}: {error}"
+        ))
+    })?;
+
+    let statement = statements.into_iter().next().ok_or_else(|| {
+        EngineError::protocol_mismatch(format!(
+            "missing read rewrite statement for {INTERNAL_STATE_VTABLE}"
+        ))
+    })?;
+
+    match statement {
+        Statement::Query(query) => Ok(*query),
+        _ => Err(EngineError::protocol_mismatch(format!(
+            "read rewrite query for {INTERNAL_STATE_VTABLE} must be a SELECT"
+        ))),
+    }
+}
+
+/// Escapes a value for embedding as a single-quoted SQL string literal by
+/// doubling embedded single quotes, the same escaping SQLite itself expects.
+fn escape_sql_string_literal(value: &str) -> String {
+    value.replace('\'', "''")
+}
+
+fn validate_validation_mutations(sql: &str) -> Result<(), EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::rewrite_validation(format!("failed to parse validation SQL: {error}"))
+    })?;
+
+    if statements.is_empty() {
+        return Err(EngineError::rewrite_validation(
+            "validation SQL must include at least one mutation statement",
+        ));
+    }
+
+    for statement in statements {
+        if !is_validation_mutation_statement(&statement) {
+            return Err(EngineError::rewrite_validation(
+                "validation statements may only mutate state or state_all",
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+fn is_validation_mutation_statement(statement: &Statement) -> bool {
+    match statement {
+        Statement::Insert(insert) => is_validation_target_name(&insert.table_name),
+        Statement::Update { table, .. } => {
+            let TableFactor::Table { name, .. } = &table.relation else {
+                return false;
+            };
+            is_validation_target_name(name)
+        }
+        Statement::Delete(delete) => {
+            let tables = match &delete.from {
+                FromTable::WithFromKeyword(value) => value,
+                FromTable::WithoutKeyword(value) => value,
+            };
+            let Some(first) = tables.first() else {
+                return false;
+            };
+            let TableFactor::Table { name, .. } = &first.relation else {
+                return false;
+            };
+            is_validation_target_name(name)
+        }
+        _ => false,
+    }
+}
+
+fn is_validation_target_name(name: &ObjectName) -> bool {
+    matches!(
+        classify_write_target(name),
+        WriteTarget::State
+            | WriteTarget::StateAll
+            | WriteTarget::StateByVersion
+            | WriteTarget::StateVtable
+    )
+}
+
+#[derive(Debug, Clone)]
+struct MutationValidationRow {
+    entity_id: Option<String>,
+    file_id: Option<String>,
+    version_id: Option<String>,
+    schema_key: String,
+    schema_version: String,
+    snapshot_content: Value,
+    conflict_merge_mode: Option<ConflictMergeMode>,
+}
+
+/// Combined output of validating a mutation statement against its schema(s):
+/// the upsert resolutions `resolve_upsert_conflict` produced, plus the
+/// `TransactionReport` describing which entities were asserted or retracted.
+#[derive(Debug, Default)]
+struct MutationValidationOutcome {
+    upsert_resolutions: Vec<UpsertResolution>,
+    transaction_report: TransactionReport,
+}
+
+fn validate_state_mutation_rows(
+    host: &dyn HostCallbacks,
+    sql: &str,
+    params: &[Value],
+    statement_kind: &str,
+) -> Result<MutationValidationOutcome, EngineError> {
+    if !should_validate_mutation(sql, statement_kind) {
+        return Ok(MutationValidationOutcome::default());
+    }
+
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::rewrite_validation(format!("failed to parse mutation SQL for validation: {error}"))
+    })?;
+
+    validate_mutation_statements(host, &statements, params)
+}
+
+/// Same validation as `validate_state_mutation_rows`, but parses through
+/// `cache` so a repeated SQL shape pays the parse cost once.
+fn validate_state_mutation_rows_cached(
+    cache: &ParseCache,
+    host: &dyn HostCallbacks,
+    sql: &str,
+    params: &[Value],
+    statement_kind: &str,
+) -> Result<MutationValidationOutcome, EngineError> {
+    if !should_validate_mutation(sql, statement_kind) {
+        return Ok(MutationValidationOutcome::default());
+    }
+
+    let statements = parse_cached(cache, sql)
+        .map_err(|error| EngineError::rewrite_validation(error.message))?;
+    validate_mutation_statements(host, &statements, params)
+}
+
+fn should_validate_mutation(sql: &str, statement_kind: &str) -> bool {
+    statement_kind == RUST_KIND_VALIDATION
+        || (statement_kind == RUST_KIND_WRITE_REWRITE && might_mutate_state_tables(sql))
+}
+
+fn validate_mutation_statements(
+    host: &dyn HostCallbacks,
+    statements: &[Statement],
+    params: &[Value],
+) -> Result<MutationValidationOutcome, EngineError> {
+    let mut param_cursor: usize = 0;
+    let mut outcome = MutationValidationOutcome::default();
+    let mut issues: Vec<ValidationIssue> = Vec::new();
+    for statement in statements {
+        let mut rows = extract_insert_validation_rows(statement, params, &mut param_cursor)?;
+        for mut row in rows.drain(..) {
+            if let Some(resolution) = resolve_upsert_conflict(host, &mut row)? {
+                outcome.upsert_resolutions.push(resolution);
+            }
+            let (schema, row_issues) = collect_mutation_row_issues(host, &row)?;
+            if !row_issues.is_empty() {
+                issues.extend(row_issues);
+                continue;
+            }
+            outcome.transaction_report.asserted.push(TransactionEffect {
+                entity_id: row.entity_id.clone(),
+                schema_key: Some(row.schema_key.clone()),
+                file_id: row.file_id.clone(),
+                version_id: row.version_id.clone(),
+                schema: Some(schema),
+            });
+        }
+
+        if let Some(effect) = extract_update_effect_rows(statement) {
+            outcome.transaction_report.asserted.push(effect);
+        }
+        if let Some(effect) = extract_delete_effect_rows(statement) {
+            outcome.transaction_report.retracted.push(effect);
+        }
+    }
+
+    if !issues.is_empty() {
+        return Err(EngineError::rewrite_validation_batch(issues));
+    }
+
+    Ok(outcome)
+}
+
+/// Extracts the identity columns an `UPDATE state[...] SET ... WHERE ...`
+/// statement asserts, from the literal-equality predicates in its WHERE
+/// clause, reusing the same equality extraction as subscription matching.
+/// `schema` is left `None`: updates to `state` don't re-validate against the
+/// stored schema today.
+fn extract_update_effect_rows(statement: &Statement) -> Option<TransactionEffect> {
+    let Statement::Update { table, selection, .. } = statement else {
+        return None;
+    };
+    let TableFactor::Table { name, .. } = &table.relation else {
+        return None;
+    };
+    if !is_validation_target_name(name) {
+        return None;
+    }
+    Some(transaction_effect_from_selection(selection.as_ref()))
+}
+
+/// Extracts the identity columns a `DELETE FROM state[...] WHERE ...`
+/// statement retracts, mirroring `extract_update_effect_rows`.
+fn extract_delete_effect_rows(statement: &Statement) -> Option<TransactionEffect> {
+    let Statement::Delete(delete) = statement else {
+        return None;
+    };
+    let tables = match &delete.from {
+        FromTable::WithFromKeyword(value) => value,
+        FromTable::WithoutKeyword(value) => value,
+    };
+    let first = tables.first()?;
+    let TableFactor::Table { name, .. } = &first.relation else {
+        return None;
+    };
+    if !is_validation_target_name(name) {
+        return None;
+    }
+    Some(transaction_effect_from_selection(delete.selection.as_ref()))
+}
+
+fn transaction_effect_from_selection(selection: Option<&Expr>) -> TransactionEffect {
+    let mut predicate = SubscriptionPredicate::default();
+    if let Some(expr) = selection {
+        collect_subscription_equalities(expr, &mut predicate);
+    }
+    TransactionEffect {
+        entity_id: predicate.entity_id,
+        schema_key: predicate.schema_key,
+        file_id: predicate.file_id,
+        version_id: predicate.version_id,
+        schema: None,
+    }
+}
+
+fn might_mutate_state_tables(sql: &str) -> bool {
+    let lowered = sql.to_lowercase();
+    lowered.contains("insert into state")
+        || lowered.contains("insert into state_by_version")
+        || lowered.contains("insert into state_all")
+        || lowered.contains("insert into lix_internal_state_vtable")
+        || lowered.contains("update state")
+        || lowered.contains("update state_by_version")
+        || lowered.contains("update state_all")
+        || lowered.contains("update lix_internal_state_vtable")
+        || lowered.contains("delete from state")
+        || lowered.contains("delete from state_by_version")
+        || lowered.contains("delete from state_all")
+        || lowered.contains("delete from lix_internal_state_vtable")
+}
+
+fn extract_insert_validation_rows(
+    statement: &Statement,
+    params: &[Value],
+    param_cursor: &mut usize,
+) -> Result<Vec<MutationValidationRow>, EngineError> {
+    let Statement::Insert(insert) = statement else {
+        return Ok(Vec::new());
+    };
+
+    if !is_validation_target_name(&insert.table_name) {
+        return Ok(Vec::new());
+    }
+
+    let Some(source) = &insert.source else {
+        return Ok(Vec::new());
+    };
+    let SetExpr::Values(values) = &*source.body else {
+        return Ok(Vec::new());
+    };
+
+    let column_names: Vec<String> = if insert.columns.is_empty() {
+        vec![
+            "entity_id".to_owned(),
+            "schema_key".to_owned(),
+            "file_id".to_owned(),
+            "plugin_key".to_owned(),
+            "snapshot_content".to_owned(),
+            "schema_version".to_owned(),
+            "metadata".to_owned(),
+            "untracked".to_owned(),
+            "version_id".to_owned(),
+        ]
+    } else {
+        insert
+            .columns
+            .iter()
+            .map(|ident| ident.value.to_lowercase())
+            .collect()
+    };
+
+    let find_column = |name: &str| column_names.iter().position(|column| column == name);
+    let schema_key_idx = find_column("schema_key").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required schema_key column")
+    })?;
+    let schema_version_idx = find_column("schema_version").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required schema_version column")
+    })?;
+    let snapshot_idx = find_column("snapshot_content").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required snapshot_content column")
+    })?;
+    let entity_id_idx = find_column("entity_id");
+    let file_id_idx = find_column("file_id");
+    let version_id_idx = find_column("version_id");
+    let conflict_merge_mode = detect_conflict_merge_mode(insert);
+
+    let mut result = Vec::with_capacity(values.rows.len());
+    for row in &values.rows {
+        if row.len() != column_names.len() {
+            return Err(EngineError::rewrite_validation(
+                "insert row shape does not match declared columns",
+            ));
+        }
+
+        let schema_key =
+            evaluate_sql_expr_to_json(&row[schema_key_idx], params, param_cursor, false)?;
+        let schema_version = evaluate_sql_expr_to_json(
+            &row[schema_version_idx],
+            params,
+            param_cursor,
+            false,
+        )?;
+        let snapshot_content =
+            evaluate_sql_expr_to_json(&row[snapshot_idx], params, param_cursor, true)?;
+
+        let schema_key = schema_key.as_str().ok_or_else(|| {
+            EngineError::rewrite_validation("schema_key must resolve to a string")
+        })?;
+        let schema_version = schema_version.as_str().ok_or_else(|| {
+            EngineError::rewrite_validation("schema_version must resolve to a string")
+        })?;
+
+        let entity_id = extract_optional_string_column(entity_id_idx, row, params, param_cursor)?;
+        let file_id = extract_optional_string_column(file_id_idx, row, params, param_cursor)?;
+        let version_id = extract_optional_string_column(version_id_idx, row, params, param_cursor)?;
+
+        result.push(MutationValidationRow {
+            entity_id,
+            file_id,
+            version_id,
+            schema_key: schema_key.to_owned(),
+            schema_version: schema_version.to_owned(),
+            snapshot_content,
+            conflict_merge_mode,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Inspects an `INSERT ... ON CONFLICT(...) DO UPDATE SET ...` clause and
+/// determines how a conflicting row's `snapshot_content` should be
+/// reconciled with the existing stored row, keyed off the exact top-level
+/// function the `DO UPDATE SET` assignment for `snapshot_content` calls
+/// (never a substring match against the rendered expression, which can't
+/// tell a real call from one buried in unrelated text): `json_patch(...)` is
+/// SQLite's own RFC 7386 merge-patch function and is genuinely a deep merge,
+/// so it maps to `DeepMerge`; the engine's `lix_shallow_merge(...)` marker
+/// (never sent to the host — the engine always substitutes the merged value
+/// before the statement is executed) maps to `ShallowMerge`; any other
+/// assignment is `Replace`. Plain inserts and `DO NOTHING` upserts have no
+/// merge mode.
+fn detect_conflict_merge_mode(insert: &Insert) -> Option<ConflictMergeMode> {
+    let OnInsert::OnConflict(on_conflict) = insert.on.as_ref()? else {
+        return None;
+    };
+    let OnConflictAction::DoUpdate(do_update) = &on_conflict.action else {
+        return None;
+    };
+    let snapshot_assignment = do_update.assignments.iter().find(|assignment| {
+        assignment
+            .id
+            .last()
+            .map(|ident| ident.value.eq_ignore_ascii_case("snapshot_content"))
+            .unwrap_or(false)
+    })?;
+
+    let Expr::Function(function) = &snapshot_assignment.value else {
+        return Some(ConflictMergeMode::Replace);
+    };
+    match function.name.to_string().to_lowercase().as_str() {
+        "json_patch" => Some(ConflictMergeMode::DeepMerge),
+        "lix_shallow_merge" => Some(ConflictMergeMode::ShallowMerge),
+        _ => Some(ConflictMergeMode::Replace),
+    }
+}
+
+/// Asks the host for the currently active version_id, the same value a
+/// `state` write is implicitly scoped to.
+fn fetch_active_version_id(host: &dyn HostCallbacks) -> Result<String, EngineError> {
+    let response = host
+        .execute(HostExecuteRequest {
+            request_id: "rust-resolve-active-version".to_owned(),
+            sql: format!("SELECT version_id FROM {ACTIVE_VERSION_VIEW} LIMIT 1"),
+            params: vec![],
+            statement_kind: RUST_KIND_PASSTHROUGH,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_REWRITE_VALIDATION))?;
+    response
+        .rows
+        .first()
+        .and_then(|row| row.get("version_id"))
+        .and_then(Value::as_str)
+        .map(str::to_owned)
+        .ok_or_else(|| {
+            EngineError::rewrite_validation("failed to resolve the active version_id for a state mutation")
+        })
+}
+
+/// Looks up the currently stored `snapshot_content` for a
+/// `STATE_MUTATION_KEY_COLUMNS` identity, returning `None` when no row
+/// matches. `version_id` is resolved from `active_version` first when it's
+/// `None` on entry (and updated in place) — a mutation against the `state`
+/// view never supplies `version_id` explicitly (the column only exists on
+/// `state_by_version`/`state_all`), so without this the lookup would bind
+/// `version_id IS NULL`, never match the real row, and every such mutation
+/// would be misreported as having no existing row.
+fn lookup_existing_state_row(
+    host: &dyn HostCallbacks,
+    entity_id: Option<&str>,
+    schema_key: Option<&str>,
+    file_id: Option<&str>,
+    version_id: &mut Option<String>,
+) -> Result<Option<Value>, EngineError> {
+    if version_id.is_none() {
+        *version_id = Some(fetch_active_version_id(host)?);
+    }
+
+    let sql = "SELECT snapshot_content FROM state_by_version \
+               WHERE entity_id IS ? AND schema_key IS ? AND file_id IS ? AND version_id IS ? \
+               LIMIT 1";
+    let response = host
+        .execute(HostExecuteRequest {
+            request_id: "rust-upsert-conflict-load".to_owned(),
+            sql: sql.to_owned(),
+            params: vec![
+                entity_id.map(|value| Value::String(value.to_owned())).unwrap_or(Value::Null),
+                schema_key.map(|value| Value::String(value.to_owned())).unwrap_or(Value::Null),
+                file_id.map(|value| Value::String(value.to_owned())).unwrap_or(Value::Null),
+                version_id.clone().map(Value::String).unwrap_or(Value::Null),
+            ],
+            statement_kind: RUST_KIND_PASSTHROUGH,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_REWRITE_VALIDATION))?;
+
+    Ok(response
+        .rows
+        .first()
+        .map(|existing| existing.get("snapshot_content").cloned().unwrap_or(Value::Null)))
+}
+
+/// Resolves a row whose statement carried an `ON CONFLICT ... DO UPDATE`
+/// clause against existing stored state: looks up the current row by
+/// `STATE_MUTATION_KEY_COLUMNS`, merges `snapshot_content` per `row`'s
+/// `conflict_merge_mode` when a match exists, and reports whether the row
+/// will be created or updated. Rows without a conflict merge mode (plain
+/// inserts) are left untouched and produce no resolution.
+fn resolve_upsert_conflict(
+    host: &dyn HostCallbacks,
+    row: &mut MutationValidationRow,
+) -> Result<Option<UpsertResolution>, EngineError> {
+    let Some(merge_mode) = row.conflict_merge_mode else {
+        return Ok(None);
+    };
+
+    let existing_snapshot = lookup_existing_state_row(
+        host,
+        row.entity_id.as_deref(),
+        Some(row.schema_key.as_str()),
+        row.file_id.as_deref(),
+        &mut row.version_id,
+    )?;
+
+    let Some(existing_snapshot) = existing_snapshot else {
+        return Ok(Some(UpsertResolution {
+            entity_id: row.entity_id.clone(),
+            schema_key: row.schema_key.clone(),
+            file_id: row.file_id.clone(),
+            version_id: row.version_id.clone(),
+            outcome: UpsertOutcome::Created,
+        }));
+    };
+
+    row.snapshot_content = match merge_mode {
+        ConflictMergeMode::Replace => row.snapshot_content.clone(),
+        ConflictMergeMode::ShallowMerge => {
+            shallow_merge_json_objects(&existing_snapshot, &row.snapshot_content)
+        }
+        ConflictMergeMode::DeepMerge => {
+            deep_merge_json_objects(&existing_snapshot, &row.snapshot_content)
+        }
+    };
+
+    Ok(Some(UpsertResolution {
+        entity_id: row.entity_id.clone(),
+        schema_key: row.schema_key.clone(),
+        file_id: row.file_id.clone(),
+        version_id: row.version_id.clone(),
+        outcome: UpsertOutcome::Updated,
+    }))
+}
+
+/// Merges `incoming` over `base` one level deep: keys present in `incoming`
+/// win, keys only in `base` are preserved. Falls back to `incoming` as-is
+/// when either side isn't a JSON object.
+fn shallow_merge_json_objects(base: &Value, incoming: &Value) -> Value {
+    let (Value::Object(base_fields), Value::Object(incoming_fields)) = (base, incoming) else {
+        return incoming.clone();
+    };
+    let mut merged = base_fields.clone();
+    for (key, value) in incoming_fields {
+        merged.insert(key.clone(), value.clone());
+    }
+    Value::Object(merged)
+}
+
+/// Merges `patch` into `base` per RFC 7386 (JSON Merge Patch), matching
+/// SQLite's `json_patch` semantics exactly: an object value for a key is
+/// merged into that key recursively, a `null` value deletes the key from
+/// `base`, anything else replaces it wholesale. Falls back to `patch` as-is
+/// when it isn't a JSON object.
+fn deep_merge_json_objects(base: &Value, patch: &Value) -> Value {
+    let Value::Object(patch_fields) = patch else {
+        return patch.clone();
+    };
+    let mut merged = match base {
+        Value::Object(base_fields) => base_fields.clone(),
+        _ => serde_json::Map::new(),
+    };
+    for (key, value) in patch_fields {
+        if value.is_null() {
+            merged.remove(key);
+        } else {
+            let existing = merged.get(key).cloned().unwrap_or(Value::Null);
+            merged.insert(key.clone(), deep_merge_json_objects(&existing, value));
+        }
+    }
+    Value::Object(merged)
+}
+
+fn extract_optional_string_column(
+    column_idx: Option<usize>,
+    row: &[Expr],
+    params: &[Value],
+    param_cursor: &mut usize,
+) -> Result<Option<String>, EngineError> {
+    let Some(idx) = column_idx else {
+        return Ok(None);
+    };
+    let value = evaluate_sql_expr_to_json(&row[idx], params, param_cursor, false)?;
+    Ok(value.as_str().map(str::to_owned))
+}
+
+fn evaluate_sql_expr_to_json(
+    expr: &Expr,
+    params: &[Value],
+    param_cursor: &mut usize,
+    parse_json_strings: bool,
+) -> Result<Value, EngineError> {
+    match expr {
+        Expr::Value(value) => convert_sql_value_to_json(value, params, param_cursor, parse_json_strings),
+        Expr::Function(function) => {
+            let function_name = function.name.to_string().to_lowercase();
+            if function_name == "json" {
+                let FunctionArguments::List(argument_list) = &function.args else {
+                    return Err(EngineError::rewrite_validation(
+                        "json(...) requires an argument list",
+                    ));
+                };
+                if argument_list.args.len() != 1 {
+                    return Err(EngineError::rewrite_validation(
+                        "json(...) requires exactly one argument",
+                    ));
+                }
+                let FunctionArg::Unnamed(FunctionArgExpr::Expr(inner)) = &argument_list.args[0]
+                else {
+                    return Err(EngineError::rewrite_validation(
+                        "json(...) only supports expression arguments in Rust validation",
+                    ));
+                };
+                let value = evaluate_sql_expr_to_json(inner, params, param_cursor, true)?;
+                return Ok(value);
+            }
+
+            Err(EngineError::rewrite_validation(format!(
+                "unsupported SQL function in state validation mutation: {function_name}"
+            )))
+        }
+        _ => Err(EngineError::rewrite_validation(format!(
+            "unsupported SQL expression in validation mutation: {expr}"
+        ))),
+    }
+}
+
+fn convert_sql_value_to_json(
+    value: &sqlparser::ast::Value,
+    params: &[Value],
+    param_cursor: &mut usize,
+    parse_json_strings: bool,
+) -> Result<Value, EngineError> {
+    match value {
+        sqlparser::ast::Value::SingleQuotedString(text)
+        | sqlparser::ast::Value::DoubleQuotedString(text)
+        | sqlparser::ast::Value::TripleSingleQuotedString(text)
+        | sqlparser::ast::Value::TripleDoubleQuotedString(text)
+        | sqlparser::ast::Value::EscapedStringLiteral(text)
+        | sqlparser::ast::Value::UnicodeStringLiteral(text)
+        | sqlparser::ast::Value::NationalStringLiteral(text) => {
+            if parse_json_strings {
+                serde_json::from_str::<Value>(text).map_err(|error| {
+                    EngineError::rewrite_validation(format!(
+                        "failed to parse JSON snapshot content: {error}"
+                    ))
+                })
+            } else {
+                Ok(Value::String(text.clone()))
+            }
+        }
+        sqlparser::ast::Value::Number(number, _) => {
+            if let Ok(parsed) = number.parse::<i64>() {
+                return Ok(Value::Number(parsed.into()));
+            }
+            if let Ok(parsed) = number.parse::<f64>() {
+                if let Some(json_number) = serde_json::Number::from_f64(parsed) {
+                    return Ok(Value::Number(json_number));
+                }
+            }
+            Err(EngineError::rewrite_validation(format!(
+                "unsupported numeric literal in validation mutation: {number}"
+            )))
+        }
+        sqlparser::ast::Value::Boolean(boolean) => Ok(Value::Bool(*boolean)),
+        sqlparser::ast::Value::Null => Ok(Value::Null),
+        sqlparser::ast::Value::Placeholder(_) => {
+            let Some(bound) = params.get(*param_cursor) else {
+                return Err(EngineError::rewrite_validation(
+                    "not enough SQL parameters for validation mutation",
+                ));
+            };
+            *param_cursor += 1;
+            if parse_json_strings {
+                if let Value::String(text) = bound {
+                    if let Ok(parsed) = serde_json::from_str::<Value>(text) {
+                        return Ok(parsed);
+                    }
+                }
+            }
+            Ok(bound.clone())
+        }
+        _ => Err(EngineError::rewrite_validation(format!(
+            "unsupported SQL literal in validation mutation: {value}"
+        ))),
+    }
+}
+
+/// Validates `row`'s snapshot against its stored schema (JSON Schema shape
+/// plus any `x-lix-constraints` CEL expressions), accumulating every
+/// violation found rather than stopping at the first one. Returns the schema
+/// document alongside the issues collected (empty when the row is valid) so
+/// the caller can attach the schema to the row's `TransactionEffect` without
+/// fetching it a second time. Only infrastructure failures — the schema
+/// isn't stored, or fails to compile as JSON Schema — short-circuit via
+/// `Err`; everything else becomes a `ValidationIssue`.
+fn collect_mutation_row_issues(
+    host: &dyn HostCallbacks,
+    row: &MutationValidationRow,
+) -> Result<(Value, Vec<ValidationIssue>), EngineError> {
+    let schema = fetch_stored_schema(host, &row.schema_key, &row.schema_version)?;
+    let mut issues = collect_cel_expression_issues(&schema, &row.schema_key);
+
+    // Validate the same snapshot `materialize_cel_row_values` will persist,
+    // not the pre-materialization one: a property that's both `required` and
+    // supplied only via `x-lix-default` must be considered satisfied here.
+    let context = cel_context_from_snapshot_row(
+        &row.snapshot_content,
+        &row.entity_id,
+        &row.file_id,
+        &row.version_id,
+    );
+    let defaulted_snapshot = materialize_default_properties(
+        &row.snapshot_content,
+        &schema,
+        &context,
+        &row.schema_key,
+        &row.schema_version,
+    )?;
+    let validated_row;
+    let row = match &defaulted_snapshot {
+        Some(snapshot_content) => {
+            validated_row = MutationValidationRow {
+                snapshot_content: snapshot_content.clone(),
+                ..row.clone()
+            };
+            &validated_row
+        }
+        None => row,
+    };
+
+    let compiled = JSONSchema::compile(&schema).map_err(|error| {
+        EngineError::rewrite_validation(format!(
+            "failed to compile schema {}@{}: {error}",
+            row.schema_key, row.schema_version
+        ))
+    })?;
+    if let Err(errors) = compiled.validate(&row.snapshot_content) {
+        for error in errors {
+            issues.push(ValidationIssue {
+                entity_id: row.entity_id.clone(),
+                schema_key: row.schema_key.clone(),
+                pointer: error.instance_path.to_string(),
+                reason: validation_error_kind_reason(&error.kind),
+            });
+        }
+    }
+    issues.extend(collect_cel_constraint_issues(&schema, row));
+    Ok((schema, issues))
+}
+
+/// Maps a `jsonschema` validation failure onto the machine-readable reason
+/// codes callers switch on, falling back to `schema_violation` for keywords
+/// we don't give a dedicated code to.
+fn validation_error_kind_reason(kind: &ValidationErrorKind) -> &'static str {
+    match kind {
+        ValidationErrorKind::Required { .. } => "missing_required",
+        ValidationErrorKind::AdditionalProperties { .. } => "additional_property",
+        ValidationErrorKind::Type { .. } => "type_mismatch",
+        _ => "schema_violation",
+    }
+}
+
+/// Evaluates the `x-lix-constraints` array (if present on the schema root) as
+/// CEL boolean expressions against `row`'s snapshot fields plus its identity
+/// columns, collecting an issue for every expression that fails to compile,
+/// fails to evaluate, or evaluates to anything other than `true`. Compiled
+/// programs are cached per `(schema_key, schema_version, expr)` so repeated
+/// rows against the same schema don't recompile CEL.
+fn collect_cel_constraint_issues(
+    schema: &Value,
+    row: &MutationValidationRow,
+) -> Vec<ValidationIssue> {
+    let Some(Value::Array(constraints)) = schema.get("x-lix-constraints") else {
+        return Vec::new();
+    };
+
+    let context = cel_context_from_snapshot_row(
+        &row.snapshot_content,
+        &row.entity_id,
+        &row.file_id,
+        &row.version_id,
+    );
+
+    let mut issues = Vec::new();
+    for constraint in constraints {
+        let Value::String(expression) = constraint else {
+            continue;
+        };
+        let program =
+            match compiled_constraint_program(&row.schema_key, &row.schema_version, expression) {
+                Ok(program) => program,
+                Err(_) => {
+                    issues.push(ValidationIssue {
+                        entity_id: row.entity_id.clone(),
+                        schema_key: row.schema_key.clone(),
+                        pointer: "/x-lix-constraints".to_owned(),
+                        reason: "invalid_cel_expression",
+                    });
+                    continue;
+                }
+            };
+        match program.execute(&context) {
+            Ok(cel_interpreter::Value::Bool(true)) => {}
+            Ok(_) => issues.push(ValidationIssue {
+                entity_id: row.entity_id.clone(),
+                schema_key: row.schema_key.clone(),
+                pointer: "/x-lix-constraints".to_owned(),
+                reason: "constraint_violation",
+            }),
+            Err(_) => issues.push(ValidationIssue {
+                entity_id: row.entity_id.clone(),
+                schema_key: row.schema_key.clone(),
+                pointer: "/x-lix-constraints".to_owned(),
+                reason: "invalid_cel_expression",
+            }),
+        }
+    }
+
+    issues
+}
+
+thread_local! {
+    static CONSTRAINT_PROGRAM_CACHE: std::cell::RefCell<std::collections::HashMap<(String, String, String), std::rc::Rc<Program>>> =
+        std::cell::RefCell::new(std::collections::HashMap::new());
+}
+
+fn compiled_constraint_program(
+    schema_key: &str,
+    schema_version: &str,
+    expression: &str,
+) -> Result<std::rc::Rc<Program>, EngineError> {
+    let cache_key = (
+        schema_key.to_owned(),
+        schema_version.to_owned(),
+        expression.to_owned(),
+    );
+    CONSTRAINT_PROGRAM_CACHE.with(|cache| {
+        if let Some(program) = cache.borrow().get(&cache_key) {
+            return Ok(program.clone());
+        }
+        let program = Program::compile(expression).map_err(|error| {
+            EngineError::rewrite_validation(format!(
+                "invalid CEL expression in x-lix-constraints: {error}"
+            ))
+        })?;
+        let program = std::rc::Rc::new(program);
+        cache
+            .borrow_mut()
+            .insert(cache_key, program.clone());
+        Ok(program)
+    })
+}
+
+fn identity_column_to_cel_value(column: &Option<String>) -> cel_interpreter::Value {
+    match column {
+        Some(text) => cel_interpreter::Value::String(std::sync::Arc::new(text.clone())),
+        None => cel_interpreter::Value::Null,
+    }
+}
+
+/// Builds a CEL evaluation context from a mutation row: every key of
+/// `snapshot_content` (when it's a JSON object) plus the row's own identity
+/// columns. Shared between `collect_cel_constraint_issues`'s `x-lix-constraints`
+/// checks and `materialize_cel_row_values`'s `x-lix-default`/
+/// `x-lix-override-lixcols` evaluation, so both see the same variables.
+fn cel_context_from_snapshot_row(
+    snapshot_content: &Value,
+    entity_id: &Option<String>,
+    file_id: &Option<String>,
+    version_id: &Option<String>,
+) -> cel_interpreter::Context {
+    let mut context = cel_interpreter::Context::default();
+    if let Value::Object(fields) = snapshot_content {
+        for (key, value) in fields {
+            context.add_variable_from_value(key.clone(), json_value_to_cel_value(value));
+        }
+    }
+    context.add_variable_from_value("entity_id", identity_column_to_cel_value(entity_id));
+    context.add_variable_from_value("file_id", identity_column_to_cel_value(file_id));
+    context.add_variable_from_value("version_id", identity_column_to_cel_value(version_id));
+    context
+}
+
+/// Converts a JSON value into the equivalent CEL value so snapshot fields can
+/// be bound into a constraint-evaluation context. Nested objects/arrays are
+/// preserved recursively; this mirrors how `convert_sql_value_to_json` walks
+/// the inverse direction for SQL literals.
+fn json_value_to_cel_value(value: &Value) -> cel_interpreter::Value {
+    match value {
+        Value::Null => cel_interpreter::Value::Null,
+        Value::Bool(flag) => cel_interpreter::Value::Bool(*flag),
+        Value::Number(number) => {
+            if let Some(parsed) = number.as_i64() {
+                cel_interpreter::Value::Int(parsed)
+            } else if let Some(parsed) = number.as_f64() {
+                cel_interpreter::Value::Float(parsed)
+            } else {
+                cel_interpreter::Value::Null
+            }
+        }
+        Value::String(text) => cel_interpreter::Value::String(std::sync::Arc::new(text.clone())),
+        Value::Array(items) => cel_interpreter::Value::List(std::sync::Arc::new(
+            items.iter().map(json_value_to_cel_value).collect(),
+        )),
+        Value::Object(fields) => {
+            let mut map = std::collections::HashMap::new();
+            for (key, value) in fields {
+                map.insert(
+                    cel_interpreter::objects::Key::String(std::sync::Arc::new(key.clone())),
+                    json_value_to_cel_value(value),
+                );
+            }
+            cel_interpreter::Value::Map(cel_interpreter::objects::Map {
+                map: std::sync::Arc::new(map),
+            })
+        }
+    }
+}
+
+/// Converts a CEL evaluation result back into JSON, the inverse of
+/// `json_value_to_cel_value`. Used to bake an `x-lix-default`/
+/// `x-lix-override-lixcols` expression's result into a mutation row.
+/// Variants with no JSON equivalent (e.g. CEL's `Duration`/`Timestamp`)
+/// collapse to `null` rather than failing the write.
+fn cel_value_to_json_value(value: cel_interpreter::Value) -> Value {
+    match value {
+        cel_interpreter::Value::Null => Value::Null,
+        cel_interpreter::Value::Bool(flag) => Value::Bool(flag),
+        cel_interpreter::Value::Int(parsed) => Value::Number(parsed.into()),
+        cel_interpreter::Value::Float(parsed) => {
+            serde_json::Number::from_f64(parsed).map(Value::Number).unwrap_or(Value::Null)
+        }
+        cel_interpreter::Value::String(text) => Value::String((*text).clone()),
+        cel_interpreter::Value::List(items) => {
+            Value::Array(items.iter().cloned().map(cel_value_to_json_value).collect())
+        }
+        cel_interpreter::Value::Map(map) => {
+            let mut object = serde_json::Map::new();
+            for (key, value) in map.map.iter() {
+                if let cel_interpreter::objects::Key::String(key) = key {
+                    object.insert((**key).clone(), cel_value_to_json_value(value.clone()));
+                }
+            }
+            Value::Object(object)
+        }
+        _ => Value::Null,
+    }
+}
+
+/// Renders a JSON value as the SQL literal that reproduces it, the inverse of
+/// `convert_sql_value_to_json`. Objects/arrays round-trip through SQLite's
+/// `json()` function so the materialized `__lix_mutation_rows` CTE stores
+/// them the same way a hand-written `json(...)` literal would.
+fn json_value_to_sql_literal(value: &Value) -> String {
+    match value {
+        Value::Null => "NULL".to_owned(),
+        Value::Bool(flag) => if *flag { "1".to_owned() } else { "0".to_owned() },
+        Value::Number(number) => number.to_string(),
+        Value::String(text) => format!("'{}'", text.replace('\'', "''")),
+        Value::Array(_) | Value::Object(_) => {
+            let serialized = serde_json::to_string(value).unwrap_or_default();
+            format!("json('{}')", serialized.replace('\'', "''"))
+        }
+    }
+}
+
+fn fetch_stored_schema(
+    host: &dyn HostCallbacks,
+    schema_key: &str,
+    schema_version: &str,
+) -> Result<Value, EngineError> {
+    let sql = "SELECT value FROM stored_schema \
+               WHERE json_extract(value, '$.\"x-lix-key\"') = ? \
+               AND json_extract(value, '$.\"x-lix-version\"') = ? \
+               ORDER BY rowid DESC LIMIT 1";
+    let response = host
+        .execute(HostExecuteRequest {
+            request_id: "rust-validation-schema-load".to_owned(),
+            sql: sql.to_owned(),
+            params: vec![
+                Value::String(schema_key.to_owned()),
+                Value::String(schema_version.to_owned()),
+            ],
+            statement_kind: RUST_KIND_PASSTHROUGH,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_REWRITE_VALIDATION))?;
+
+    let Some(first_row) = response.rows.first() else {
+        return Err(EngineError::rewrite_validation(format!(
+            "schema {}@{} is not stored",
+            schema_key, schema_version
+        )));
+    };
+
+    match first_row {
+        Value::Object(record) => {
+            let Some(value) = record.get("value") else {
+                return Err(EngineError::rewrite_validation(
+                    "stored_schema row missing 'value' column",
+                ));
+            };
+            if let Value::String(text) = value {
+                serde_json::from_str::<Value>(text).map_err(|error| {
+                    EngineError::rewrite_validation(format!(
+                        "stored schema payload is not valid JSON: {error}"
+                    ))
+                })
+            } else {
+                Ok(value.clone())
+            }
+        }
+        Value::String(text) => serde_json::from_str::<Value>(text).map_err(|error| {
+            EngineError::rewrite_validation(format!(
+                "stored schema payload is not valid JSON: {error}"
+            ))
+        }),
+        _ => Err(EngineError::rewrite_validation(
+            "stored schema query returned an unsupported row shape",
+        )),
+    }
+}
+
+/// Batch-collecting counterpart to `validate_cel_expressions_in_schema`: walks
+/// the same `x-lix-default`/`x-lix-override-lixcols` keywords but, instead of
+/// failing on the first bad expression, records an `invalid_cel_expression`
+/// issue (tagged with the JSON pointer of the offending keyword) for every
+/// one that doesn't compile, so a schema with several broken expressions
+/// reports all of them in one round trip.
+fn collect_cel_expression_issues(schema: &Value, schema_key: &str) -> Vec<ValidationIssue> {
+    fn walk(schema: &Value, schema_key: &str, pointer: &str, issues: &mut Vec<ValidationIssue>) {
+        match schema {
+            Value::Object(record) => {
+                if let Some(Value::String(expression)) = record.get("x-lix-default") {
+                    if Program::compile(expression).is_err() {
+                        issues.push(ValidationIssue {
+                            entity_id: None,
+                            schema_key: schema_key.to_owned(),
+                            pointer: format!("{pointer}/x-lix-default"),
+                            reason: "invalid_cel_expression",
+                        });
+                    }
+                }
+                if let Some(Value::Object(overrides)) = record.get("x-lix-override-lixcols") {
+                    for (key, value) in overrides {
+                        if let Value::String(expression) = value {
+                            if Program::compile(expression).is_err() {
+                                issues.push(ValidationIssue {
+                                    entity_id: None,
+                                    schema_key: schema_key.to_owned(),
+                                    pointer: format!("{pointer}/x-lix-override-lixcols/{key}"),
+                                    reason: "invalid_cel_expression",
+                                });
+                            }
+                        }
+                    }
+                }
+                for (key, value) in record {
+                    walk(value, schema_key, &format!("{pointer}/{key}"), issues);
+                }
+            }
+            Value::Array(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    walk(value, schema_key, &format!("{pointer}/{index}"), issues);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut issues = Vec::new();
+    walk(schema, schema_key, "", &mut issues);
+    issues
+}
+
+fn validate_cel_expressions_in_schema(schema: &Value) -> Result<(), EngineError> {
+    match schema {
+        Value::Object(record) => {
+            if let Some(Value::String(expression)) = record.get("x-lix-default") {
+                Program::compile(expression).map_err(|error| {
+                    EngineError::rewrite_validation(format!(
+                        "invalid CEL expression in x-lix-default: {error}"
+                    ))
+                })?;
+            }
+            if let Some(Value::Object(overrides)) = record.get("x-lix-override-lixcols") {
+                for (key, value) in overrides {
+                    if let Value::String(expression) = value {
+                        Program::compile(expression).map_err(|error| {
+                            EngineError::rewrite_validation(format!(
+                                "invalid CEL expression in x-lix-override-lixcols.{key}: {error}"
+                            ))
+                        })?;
+                    }
+                }
+            }
+            for value in record.values() {
+                validate_cel_expressions_in_schema(value)?;
+            }
+            Ok(())
+        }
+        Value::Array(values) => {
+            for value in values {
+                validate_cel_expressions_in_schema(value)?;
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// A per-row rewrite override, produced by `materialize_cel_row_values` (the
+/// `snapshot_content` merged with any `x-lix-default` expressions for schema
+/// properties the row's JSON object omitted, plus any `x-lix-override-lixcols`
+/// expressions evaluated unconditionally for lix columns the insert already
+/// supplies) and/or by `apply_upsert_merge_to_materializations` (the
+/// `snapshot_content` resolved by an `ON CONFLICT DO UPDATE` merge, so the row
+/// that's persisted matches the row validation already checked).
+#[derive(Debug, Default)]
+struct RowCelMaterialization {
+    snapshot_content: Option<Value>,
+    lixcol_overrides: Vec<(String, Value)>,
+}
+
+impl RowCelMaterialization {
+    fn is_empty(&self) -> bool {
+        self.snapshot_content.is_none() && self.lixcol_overrides.is_empty()
+    }
+}
+
+/// Resolves `x-lix-default` and `x-lix-override-lixcols` CEL expressions for
+/// every row of a state-family `INSERT`, the evaluating counterpart to
+/// `validate_cel_expressions_in_schema`'s compile-only check. For each row,
+/// fetches the stored schema the same way `collect_mutation_row_issues`
+/// does, then: for every schema property carrying `x-lix-default` that the
+/// row's `snapshot_content` object doesn't already set, evaluates the CEL
+/// expression against `cel_context_from_snapshot_row` and merges the result
+/// in; for every `x-lix-override-lixcols` entry whose named column the
+/// insert supplies, evaluates the expression and records it as an
+/// unconditional override. Returns `None` when the statement isn't a
+/// state-family insert with a `snapshot_content` column, or no row produced
+/// any materialization.
+fn materialize_cel_row_values(
+    host: &dyn HostCallbacks,
+    insert: &Insert,
+    params: &[Value],
+) -> Result<Option<Vec<RowCelMaterialization>>, EngineError> {
+    if !is_validation_target_name(&insert.table_name) || insert.columns.is_empty() {
+        return Ok(None);
+    }
+    let Some(source) = &insert.source else {
+        return Ok(None);
+    };
+    let SetExpr::Values(values) = &*source.body else {
+        return Ok(None);
+    };
+
+    let column_names: Vec<String> = insert
+        .columns
+        .iter()
+        .map(|ident| ident.value.to_lowercase())
+        .collect();
+    let find_column = |name: &str| column_names.iter().position(|column| column == name);
+    let Some(schema_key_idx) = find_column("schema_key") else {
+        return Ok(None);
+    };
+    let Some(schema_version_idx) = find_column("schema_version") else {
+        return Ok(None);
+    };
+    let Some(snapshot_idx) = find_column("snapshot_content") else {
+        return Ok(None);
+    };
+    let entity_id_idx = find_column("entity_id");
+    let file_id_idx = find_column("file_id");
+    let version_id_idx = find_column("version_id");
+
+    let mut param_cursor: usize = 0;
+    let mut per_row: Vec<RowCelMaterialization> = Vec::with_capacity(values.rows.len());
+    let mut any_changes = false;
+
+    for row in &values.rows {
+        if row.len() != column_names.len() {
+            return Err(EngineError::rewrite_validation(
+                "insert row shape does not match declared columns",
+            ));
+        }
+
+        let schema_key =
+            evaluate_sql_expr_to_json(&row[schema_key_idx], params, &mut param_cursor, false)?;
+        let schema_version =
+            evaluate_sql_expr_to_json(&row[schema_version_idx], params, &mut param_cursor, false)?;
+        let snapshot_content =
+            evaluate_sql_expr_to_json(&row[snapshot_idx], params, &mut param_cursor, true)?;
+        let schema_key = schema_key.as_str().unwrap_or_default().to_owned();
+        let schema_version = schema_version.as_str().unwrap_or_default().to_owned();
+        let entity_id = extract_optional_string_column(entity_id_idx, row, params, &mut param_cursor)?;
+        let file_id = extract_optional_string_column(file_id_idx, row, params, &mut param_cursor)?;
+        let version_id = extract_optional_string_column(version_id_idx, row, params, &mut param_cursor)?;
+
+        let schema = fetch_stored_schema(host, &schema_key, &schema_version)?;
+        let context =
+            cel_context_from_snapshot_row(&snapshot_content, &entity_id, &file_id, &version_id);
+
+        let mut materialization = RowCelMaterialization::default();
+
+        if let Some(defaulted) = materialize_default_properties(
+            &snapshot_content,
+            &schema,
+            &context,
+            &schema_key,
+            &schema_version,
+        )? {
+            materialization.snapshot_content = Some(defaulted);
+        }
+
+        if let Some(Value::Object(overrides)) = schema.get("x-lix-override-lixcols") {
+            for (column, expression) in overrides {
+                let Value::String(expression) = expression else {
+                    continue;
+                };
+                if find_column(column).is_none() {
+                    continue;
+                }
+                let value = evaluate_cel_materialization_expression(
+                    &context,
+                    expression,
+                    &schema_key,
+                    &schema_version,
+                    column,
+                )?;
+                materialization.lixcol_overrides.push((column.clone(), value));
+            }
+        }
+
+        any_changes |= !materialization.is_empty();
+        per_row.push(materialization);
+    }
+
+    if !any_changes {
+        return Ok(None);
+    }
+    Ok(Some(per_row))
+}
+
+/// Merges `x-lix-default` values into `snapshot_content` for every
+/// `schema.properties` entry it doesn't already set, evaluating each
+/// `x-lix-default` CEL expression against `context`. Returns `None` when
+/// `snapshot_content`/`schema.properties` aren't both objects, or no
+/// property needed defaulting — the same "did anything change" signal
+/// `materialize_cel_row_values` uses to decide whether to bake in a new
+/// VALUES row. Shared with `collect_mutation_row_issues`, which needs the
+/// defaulted snapshot to validate against rather than the raw one: a
+/// property that's both `required` and satisfied only via `x-lix-default`
+/// would otherwise fail schema validation before the default ever had a
+/// chance to apply.
+fn materialize_default_properties(
+    snapshot_content: &Value,
+    schema: &Value,
+    context: &cel_interpreter::Context,
+    schema_key: &str,
+    schema_version: &str,
+) -> Result<Option<Value>, EngineError> {
+    let (Value::Object(existing_fields), Some(Value::Object(properties))) =
+        (snapshot_content, schema.get("properties"))
+    else {
+        return Ok(None);
+    };
+
+    let mut merged = existing_fields.clone();
+    let mut changed = false;
+    for (property, property_schema) in properties {
+        if merged.contains_key(property) {
+            continue;
+        }
+        let Some(Value::String(expression)) = property_schema.get("x-lix-default") else {
+            continue;
+        };
+        let value = evaluate_cel_materialization_expression(
+            context,
+            expression,
+            schema_key,
+            schema_version,
+            property,
+        )?;
+        merged.insert(property.clone(), value);
+        changed = true;
+    }
+
+    if changed {
+        Ok(Some(Value::Object(merged)))
+    } else {
+        Ok(None)
+    }
+}
+
+fn evaluate_cel_materialization_expression(
+    context: &cel_interpreter::Context,
+    expression: &str,
+    schema_key: &str,
+    schema_version: &str,
+    column: &str,
+) -> Result<Value, EngineError> {
+    let program = compiled_constraint_program(schema_key, schema_version, expression)?;
+    let result = program.execute(context).map_err(|error| {
+        EngineError::rewrite_validation(format!(
+            "x-lix-default/x-lix-override-lixcols expression for `{column}` failed to evaluate \
+             for {schema_key}@{schema_version}: {error}"
+        ))
+    })?;
+    Ok(cel_value_to_json_value(result))
+}
+
+fn rewrite_statement_for_write_rewrite(
+    statement: &Statement,
+) -> Result<(String, bool), EngineError> {
+    let rewritten = match statement {
+        Statement::Insert(insert) => rewrite_insert_for_write_rewrite(insert)?,
+        Statement::Update {
+            table,
+            assignments,
+            from,
+            selection,
+            returning,
+            ..
+        } => rewrite_update_for_write_rewrite(
+            table,
+            assignments.as_slice(),
+            from,
+            selection,
+            returning,
+        ),
+        Statement::Delete(delete) => rewrite_delete_for_write_rewrite(delete),
+        _ => None,
+    };
+
+    if let Some(sql) = rewritten {
+        Ok((sql, true))
+    } else {
+        Ok((statement.to_string(), false))
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WriteTarget {
+    State,
+    StateAll,
+    StateByVersion,
+    StateVtable,
+    Other,
+}
+
+fn classify_write_target(name: &ObjectName) -> WriteTarget {
+    let Some(last) = name.0.last() else {
+        return WriteTarget::Other;
+    };
+    let value = last.value.as_str();
+    if value.eq_ignore_ascii_case(STATE_VIEW) {
+        return WriteTarget::State;
+    }
+    if value.eq_ignore_ascii_case(STATE_ALL_VIEW) {
+        return WriteTarget::StateAll;
+    }
+    if value.eq_ignore_ascii_case(STATE_BY_VERSION) {
+        return WriteTarget::StateByVersion;
+    }
+    if value.eq_ignore_ascii_case(INTERNAL_STATE_VTABLE) {
+        return WriteTarget::StateVtable;
+    }
+    WriteTarget::Other
+}
+
+fn resolve_physical_target(target: WriteTarget) -> Option<&'static str> {
+    match target {
+        WriteTarget::State | WriteTarget::StateAll | WriteTarget::StateByVersion => {
+            Some(STATE_BY_VERSION)
+        }
+        WriteTarget::StateVtable => Some(INTERNAL_STATE_VTABLE),
+        WriteTarget::Other => None,
+    }
+}
+
+fn rewrite_insert_for_write_rewrite(insert: &Insert) -> Result<Option<String>, EngineError> {
+    rewrite_insert_for_write_rewrite_with_materializations(insert, None)
+}
+
+/// Same rewrite as `rewrite_insert_for_write_rewrite`, but additionally bakes
+/// `row_materializations` (the `x-lix-default`/`x-lix-override-lixcols`
+/// results `materialize_cel_row_values` computed, plus any upsert-merge
+/// resolution `apply_upsert_merge_to_materializations` computed) into each
+/// VALUES row before materializing the `__lix_mutation_rows` CTE:
+/// `snapshot_content` is replaced wholesale when a row's materialization
+/// supplies one, and each `lixcol_overrides` entry replaces the corresponding
+/// column's literal. A `DO UPDATE SET` clause has its `snapshot_content`
+/// assignment rewritten through `render_do_update_assignment` so a merging
+/// conflict mode doesn't re-run the caller's merge expression against the
+/// already-merged value baked into the VALUES row.
+fn rewrite_insert_for_write_rewrite_with_materializations(
+    insert: &Insert,
+    row_materializations: Option<Vec<RowCelMaterialization>>,
+) -> Result<Option<String>, EngineError> {
+    if insert.partitioned.is_some() || !insert.after_columns.is_empty() || insert.table_alias.is_some() {
+        return Ok(None);
+    }
+
+    let conflict_merge_mode = detect_conflict_merge_mode(insert);
+
+    let on_conflict_sql = match insert.on.as_ref() {
+        None => None,
+        Some(OnInsert::OnConflict(on_conflict)) => {
+            let key_columns_sql = STATE_MUTATION_KEY_COLUMNS
+                .iter()
+                .map(|column| quote_ident(column))
+                .collect::<Vec<String>>()
+                .join(", ");
+            match &on_conflict.action {
+                OnConflictAction::DoUpdate(do_update) => {
+                    let assignments_sql = do_update
+                        .assignments
+                        .iter()
+                        .map(|assignment| {
+                            render_do_update_assignment(assignment, conflict_merge_mode)
+                        })
+                        .collect::<Vec<String>>()
+                        .join(", ");
+                    Some(format!(
+                        " ON CONFLICT ({key_columns_sql}) DO UPDATE SET {assignments_sql}"
+                    ))
+                }
+                OnConflictAction::DoNothing => {
+                    Some(format!(" ON CONFLICT ({key_columns_sql}) DO NOTHING"))
+                }
+            }
+        }
+        Some(OnInsert::DuplicateKeyUpdate(_)) => return Ok(None),
+    };
+
+    let target_kind = classify_write_target(&insert.table_name);
+    let Some(target_table) = resolve_physical_target(target_kind) else {
+        return Ok(None);
+    };
+
+    let Some(source) = &insert.source else {
+        return Ok(None);
+    };
+
+    let SetExpr::Values(values) = &*source.body else {
+        return Ok(None);
+    };
+
+    if insert.columns.is_empty() {
+        return Ok(None);
+    }
+
+    let mut materialized_columns: Vec<String> = insert
+        .columns
+        .iter()
+        .map(|column| column.value.clone())
+        .collect();
+    let needs_active_version = target_kind == WriteTarget::State
+        && !materialized_columns
+            .iter()
+            .any(|column| column.eq_ignore_ascii_case("version_id"));
+    if needs_active_version {
+        materialized_columns.push("version_id".to_owned());
+    }
+
+    let snapshot_column_idx = insert
+        .columns
+        .iter()
+        .position(|column| column.value.eq_ignore_ascii_case("snapshot_content"));
+
+    let mut rendered_rows: Vec<String> = Vec::with_capacity(values.rows.len());
+    for (row_idx, row) in values.rows.iter().enumerate() {
+        if row.len() != insert.columns.len() {
+            return Err(EngineError::protocol_mismatch(
+                "insert row shape does not match declared columns",
+            ));
+        }
+
+        let mut rendered_exprs: Vec<String> = row.iter().map(ToString::to_string).collect();
+
+        if let Some(materialization) =
+            row_materializations.as_ref().and_then(|rows| rows.get(row_idx))
+        {
+            if let (Some(idx), Some(snapshot)) =
+                (snapshot_column_idx, &materialization.snapshot_content)
+            {
+                rendered_exprs[idx] = json_value_to_sql_literal(snapshot);
+            }
+            for (column, value) in &materialization.lixcol_overrides {
+                if let Some(idx) = insert
+                    .columns
+                    .iter()
+                    .position(|ident| ident.value.eq_ignore_ascii_case(column))
+                {
+                    rendered_exprs[idx] = json_value_to_sql_literal(value);
+                }
+            }
+        }
+
+        if needs_active_version {
+            rendered_exprs.push("(SELECT version_id FROM active_version)".to_owned());
+        }
+        rendered_rows.push(format!("({})", rendered_exprs.join(", ")));
+    }
+
+    let materialized_columns_sql = materialized_columns
+        .iter()
+        .map(|column| quote_ident(column))
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let sql = format!(
+        "WITH \"{MUTATION_ROW_CTE}\" ({materialized_columns_sql}) AS (VALUES {}) \
+         INSERT INTO {target_table} ({materialized_columns_sql}) \
+         SELECT {materialized_columns_sql} FROM \"{MUTATION_ROW_CTE}\"{}{}",
+        rendered_rows.join(", "),
+        on_conflict_sql.unwrap_or_default(),
+        returning_clause_sql(insert.returning.as_ref())
+    );
+
+    Ok(Some(sql))
+}
+
+/// Renders a single `DO UPDATE SET` assignment for the rewritten upsert. The
+/// `snapshot_content` assignment under a merging conflict mode
+/// (`ShallowMerge`/`DeepMerge`) is rendered as a plain `excluded."snapshot_content"`
+/// instead of the caller's original expression: the merge already ran once, in
+/// memory, against the row's validated content (see `resolve_upsert_conflict`
+/// via `apply_upsert_merge_to_materializations`), and that merged result was
+/// baked into this row's `VALUES` entry, so re-running the caller's merge
+/// expression (e.g. `json_patch(...)`) against the already-merged value would
+/// merge it a second time and diverge from what validation checked. Every
+/// other assignment — including `snapshot_content` under `Replace` — is
+/// rendered verbatim.
+fn render_do_update_assignment(
+    assignment: &sqlparser::ast::Assignment,
+    conflict_merge_mode: Option<ConflictMergeMode>,
+) -> String {
+    let is_merged_snapshot_assignment = matches!(
+        conflict_merge_mode,
+        Some(ConflictMergeMode::ShallowMerge) | Some(ConflictMergeMode::DeepMerge)
+    ) && assignment
+        .id
+        .last()
+        .map(|ident| ident.value.eq_ignore_ascii_case("snapshot_content"))
+        .unwrap_or(false);
+
+    if is_merged_snapshot_assignment {
+        "\"snapshot_content\" = excluded.\"snapshot_content\"".to_owned()
+    } else {
+        assignment.to_string()
+    }
+}
+
+/// Renders a `RETURNING <items>` suffix for a rewritten INSERT/UPDATE/DELETE,
+/// reusing SQLite's native RETURNING support on the physical statement rather
+/// than re-querying the target afterwards, so the deterministic
+/// `__lix_mutation_rows` CTE stays the single source of truth for which rows
+/// were touched. Returns an empty string when there is nothing to return.
+fn returning_clause_sql(returning: Option<&Vec<sqlparser::ast::SelectItem>>) -> String {
+    match returning {
+        Some(items) if !items.is_empty() => {
+            let items_sql = items
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!(" RETURNING {items_sql}")
+        }
+        _ => String::new(),
+    }
+}
+
+fn rewrite_update_for_write_rewrite(
+    table: &TableWithJoins,
+    assignments: &[sqlparser::ast::Assignment],
+    from: &Option<TableWithJoins>,
+    selection: &Option<sqlparser::ast::Expr>,
+    returning: &Option<Vec<sqlparser::ast::SelectItem>>,
+) -> Option<String> {
+    if table.joins.len() > 0 || from.is_some() {
+        return None;
+    }
+    let TableFactor::Table {
+        name, alias, args, ..
+    } = &table.relation
+    else {
+        return None;
+    };
+
+    if alias.is_some() || args.is_some() {
+        return None;
+    }
+
+    let target_kind = classify_write_target(name);
+    let target_table = resolve_physical_target(target_kind)?;
+
+    let predicate = combine_write_predicate(selection, target_kind);
+    let assignments_sql = assignments
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<String>>()
+        .join(", ");
+
+    let key_columns_sql = STATE_MUTATION_KEY_COLUMNS.join(", ");
+    let where_clause = match predicate {
+        Some(predicate_sql) => format!(" WHERE {predicate_sql}"),
+        None => String::new(),
+    };
+
+    Some(format!(
+        "WITH \"{MUTATION_ROW_CTE}\" AS (\
+            SELECT {key_columns_sql} \
+            FROM {target_table}{where_clause} \
+            ORDER BY {key_columns_sql}\
+        ) \
+        UPDATE {target_table} \
+        SET {assignments_sql} \
+        WHERE ({key_columns_sql}) IN (\
+            SELECT {key_columns_sql} FROM \"{MUTATION_ROW_CTE}\"\
+        ){}",
+        returning_clause_sql(returning.as_ref())
+    ))
+}
+
+fn rewrite_delete_for_write_rewrite(delete: &Delete) -> Option<String> {
+    if !delete.tables.is_empty()
+        || delete.using.is_some()
+        || !delete.order_by.is_empty()
+        || delete.limit.is_some()
+    {
+        return None;
+    }
+
+    let tables = match &delete.from {
+        FromTable::WithFromKeyword(value) => value,
+        FromTable::WithoutKeyword(value) => value,
+    };
+    if tables.len() != 1 {
+        return None;
+    }
+
+    let table_with_joins = tables.first()?;
+    if !table_with_joins.joins.is_empty() {
+        return None;
+    }
+
+    let TableFactor::Table {
+        name, alias, args, ..
+    } = &table_with_joins.relation
+    else {
+        return None;
+    };
+    if alias.is_some() || args.is_some() {
+        return None;
+    }
+
+    let target_kind = classify_write_target(name);
+    let target_table = resolve_physical_target(target_kind)?;
+    let predicate = combine_write_predicate(&delete.selection, target_kind);
+    let key_columns_sql = STATE_MUTATION_KEY_COLUMNS.join(", ");
+    let where_clause = match predicate {
+        Some(predicate_sql) => format!(" WHERE {predicate_sql}"),
+        None => String::new(),
+    };
+
+    Some(format!(
+        "WITH \"{MUTATION_ROW_CTE}\" AS (\
+            SELECT {key_columns_sql} \
+            FROM {target_table}{where_clause} \
+            ORDER BY {key_columns_sql}\
+        ) \
+        DELETE FROM {target_table} \
+        WHERE ({key_columns_sql}) IN (\
+            SELECT {key_columns_sql} FROM \"{MUTATION_ROW_CTE}\"\
+        ){}",
+        returning_clause_sql(delete.returning.as_ref())
+    ))
+}
+
+fn combine_write_predicate(
+    selection: &Option<sqlparser::ast::Expr>,
+    target: WriteTarget,
+) -> Option<String> {
+    let active_version_filter = "version_id IN (SELECT version_id FROM active_version)";
+
+    let selection_sql = selection.as_ref().map(ToString::to_string);
+
+    if target == WriteTarget::State {
+        return match selection_sql {
+            Some(sql) => Some(format!("({sql}) AND ({active_version_filter})")),
+            None => Some(active_version_filter.to_owned()),
+        };
+    }
+
+    selection_sql
+}
+
+fn quote_ident(ident: &str) -> String {
+    format!("\"{}\"", ident.replace('"', "\"\""))
+}
+
+fn execute_plugin_change_detection(
+    host: &dyn HostCallbacks,
+    request_id: &str,
+    requests: &[PluginChangeRequest],
+) -> Result<Vec<Value>, EngineError> {
+    let mut all_changes = Vec::new();
+
+    for request in requests {
+        let response = host
+            .detect_changes(HostDetectChangesRequest {
+                request_id: request_id.to_owned(),
+                plugin_key: request.plugin_key.clone(),
+                before: request.before.clone(),
+                after: request.after.clone(),
+            })
+            .map_err(|error| map_host_error(error, LIX_RUST_DETECT_CHANGES))?;
+
+        all_changes.extend(response.changes);
+    }
+
+    Ok(all_changes)
+}
+
+/// The physical write target(s) and involved `schema_key` values a mutation
+/// statement touches, as determined by `analyze_plugin_change_detection_target`.
+/// A structured descriptor rather than a bool so callers can see *which*
+/// schema keys were written, not just whether detection should run at all —
+/// e.g. to later skip detection for schema keys no registered plugin
+/// actually handles.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct PluginChangeDetectionTarget {
+    writes_file_table: bool,
+    schema_keys: std::collections::BTreeSet<String>,
+}
+
+impl PluginChangeDetectionTarget {
+    fn should_detect_changes(&self) -> bool {
+        self.writes_file_table || self.schema_keys.contains(LIX_FILE_SCHEMA_KEY)
+    }
+}
+
+fn should_run_plugin_change_detection(
+    statement_kind: &str,
+    sql: &str,
+    params: &[Value],
+) -> Result<bool, EngineError> {
+    if statement_kind != RUST_KIND_WRITE_REWRITE && statement_kind != RUST_KIND_VALIDATION {
+        return Ok(false);
+    }
+    let target = analyze_plugin_change_detection_target(sql, params)?;
+    Ok(target.should_detect_changes())
+}
+
+/// Walks the parsed `Statement`s for `sql` to find their actual physical
+/// write targets and the `schema_key` values bound to `state`-family writes,
+/// replacing the old lowercase `contains("lix_file")` check that misfired on
+/// identifiers like `filevault`, quoted/aliased names, and string literals
+/// that merely mention `lix_file`. Reuses `classify_write_target`/
+/// `is_validation_target_name` for the state family; a table literally named
+/// `file` is matched by `ObjectName` equality, which a CTE aliasing some
+/// other subquery to `file` would not satisfy.
+fn analyze_plugin_change_detection_target(
+    sql: &str,
+    params: &[Value],
+) -> Result<PluginChangeDetectionTarget, EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!(
+            "failed to parse SQL for plugin change detection: {error}"
+        ))
+    })?;
+
+    let mut target = PluginChangeDetectionTarget::default();
+    let mut param_cursor: usize = 0;
+    for statement in &statements {
+        collect_plugin_change_detection_target(statement, params, &mut param_cursor, &mut target)?;
+    }
+    Ok(target)
+}
+
+fn collect_plugin_change_detection_target(
+    statement: &Statement,
+    params: &[Value],
+    param_cursor: &mut usize,
+    target: &mut PluginChangeDetectionTarget,
+) -> Result<(), EngineError> {
+    match statement {
+        Statement::Insert(insert) => {
+            if is_file_table_name(&insert.table_name) {
+                target.writes_file_table = true;
+                return Ok(());
+            }
+            if is_validation_target_name(&insert.table_name) {
+                collect_insert_schema_keys(insert, params, param_cursor, target)?;
+            }
+        }
+        Statement::Update {
+            table, selection, ..
+        } => {
+            let TableFactor::Table { name, .. } = &table.relation else {
+                return Ok(());
+            };
+            if is_file_table_name(name) {
+                target.writes_file_table = true;
+                return Ok(());
+            }
+            if is_validation_target_name(name) {
+                if let Some(selection) = selection {
+                    collect_selection_schema_keys(selection, target);
+                }
+            }
+        }
+        Statement::Delete(delete) => {
+            let tables = match &delete.from {
+                FromTable::WithFromKeyword(value) => value,
+                FromTable::WithoutKeyword(value) => value,
+            };
+            let Some(first) = tables.first() else {
+                return Ok(());
+            };
+            let TableFactor::Table { name, .. } = &first.relation else {
+                return Ok(());
+            };
+            if is_file_table_name(name) {
+                target.writes_file_table = true;
+                return Ok(());
+            }
+            if is_validation_target_name(name) {
+                if let Some(selection) = &delete.selection {
+                    collect_selection_schema_keys(selection, target);
+                }
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+fn is_file_table_name(name: &ObjectName) -> bool {
+    name.0
+        .last()
+        .map(|part| part.value.eq_ignore_ascii_case(FILE_TABLE))
+        .unwrap_or(false)
+}
+
+/// Resolves the `schema_key` column's bound value for every row of an
+/// `INSERT INTO state[...]` statement, advancing `param_cursor` across every
+/// column (not just `schema_key`) so placeholders downstream of it stay
+/// aligned. An insert without an explicit column list is skipped: its
+/// `schema_key` position can't be located without the table's schema, so
+/// this conservatively reports no schema keys for that statement rather than
+/// guessing (it simply won't trigger detection for that shape).
+fn collect_insert_schema_keys(
+    insert: &Insert,
+    params: &[Value],
+    param_cursor: &mut usize,
+    target: &mut PluginChangeDetectionTarget,
+) -> Result<(), EngineError> {
+    if insert.columns.is_empty() {
+        return Ok(());
+    }
+    let Some(source) = &insert.source else {
+        return Ok(());
+    };
+    let SetExpr::Values(values) = &*source.body else {
+        return Ok(());
+    };
+
+    let schema_key_idx = insert
+        .columns
+        .iter()
+        .position(|ident| ident.value.eq_ignore_ascii_case("schema_key"));
+
+    for row in &values.rows {
+        for (idx, expr) in row.iter().enumerate() {
+            let value = evaluate_sql_expr_to_json(expr, params, param_cursor, false)?;
+            if Some(idx) == schema_key_idx {
+                if let Some(schema_key) = value.as_str() {
+                    target.schema_keys.insert(schema_key.to_owned());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves the `schema_key` literal equality out of an UPDATE/DELETE's
+/// WHERE clause, the same AND-joined-equalities shape
+/// `collect_subscription_equalities` already understands.
+fn collect_selection_schema_keys(selection: &Expr, target: &mut PluginChangeDetectionTarget) {
+    let mut predicate = SubscriptionPredicate::default();
+    collect_subscription_equalities(selection, &mut predicate);
+    if let Some(schema_key) = predicate.schema_key {
+        target.schema_keys.insert(schema_key);
+    }
+}
+
+fn map_host_error(error: EngineError, default_code: &'static str) -> EngineError {
+    if error.code == LIX_RUST_SQLITE_EXECUTION
+        || error.code == LIX_RUST_DETECT_CHANGES
+        || error.code == LIX_RUST_REWRITE_VALIDATION
+        || error.code == LIX_RUST_UNSUPPORTED_SQLITE_FEATURE
+        || error.code == LIX_RUST_PROTOCOL_MISMATCH
+        || error.code == LIX_RUST_TIMEOUT
+        || error.code == LIX_RUST_UNKNOWN
+        || error.code == LIX_RUST_QUOTA_EXCEEDED
+    {
+        return error;
+    }
+
+    EngineError::new(default_code, error.message)
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SubscriptionEvent {
+    Columns {
+        columns: Vec<&'static str>,
+    },
+    Row {
+        entity_id: String,
+        cells: Value,
+    },
+    Change {
+        kind: ChangeKind,
+        entity_id: String,
+        cells: Value,
+    },
+    EndOfQuery,
+}
+
+/// Delivers `SubscriptionEvent`s for a given `subscription_id`. Modeled on
+/// `HostCallbacks`: a thin trait boundary so the engine stays agnostic of how
+/// the host actually ships events to a reactive consumer.
+pub trait SubscriptionCallbacks {
+    fn emit(&self, subscription_id: &str, event: SubscriptionEvent);
+}
+
+#[derive(Debug, Clone, Default)]
+struct SubscriptionPredicate {
+    schema_key: Option<String>,
+    file_id: Option<String>,
+    entity_id: Option<String>,
+    version_id: Option<String>,
+}
+
+impl SubscriptionPredicate {
+    fn matches(&self, row: &MutationKeyRow) -> bool {
+        matches_predicate_field(&self.schema_key, &row.schema_key)
+            && matches_predicate_field(&self.file_id, &row.file_id)
+            && matches_predicate_field(&self.entity_id, &row.entity_id)
+            && matches_optional_predicate_field(&self.version_id, row.version_id.as_deref())
+    }
+
+    /// Same comparison as `matches`, but against a `TransactionEffect` whose
+    /// own identity columns are partial (an UPDATE/DELETE's WHERE clause
+    /// rarely pins down every column). Two partial predicates are compatible
+    /// when every column present in both agree; a column either side left
+    /// unconstrained can't rule the match out.
+    fn compatible_with_effect(&self, effect: &TransactionEffect) -> bool {
+        fields_compatible(&self.schema_key, &effect.schema_key)
+            && fields_compatible(&self.file_id, &effect.file_id)
+            && fields_compatible(&self.entity_id, &effect.entity_id)
+            && fields_compatible(&self.version_id, &effect.version_id)
+    }
+}
+
+fn fields_compatible(left: &Option<String>, right: &Option<String>) -> bool {
+    match (left, right) {
+        (Some(left), Some(right)) => left == right,
+        _ => true,
+    }
+}
+
+fn matches_predicate_field(predicate: &Option<String>, actual: &str) -> bool {
+    predicate.as_deref().map(|value| value == actual).unwrap_or(true)
+}
+
+fn matches_optional_predicate_field(predicate: &Option<String>, actual: Option<&str>) -> bool {
+    match (predicate, actual) {
+        (None, _) => true,
+        (Some(expected), Some(actual)) => expected == actual,
+        (Some(_), None) => false,
+    }
+}
+
+struct SubscriptionMatcher {
+    subscription_id: String,
+    predicate: SubscriptionPredicate,
+}
+
+/// Registry of live query subscriptions over `state`/`state_all`/
+/// `lix_internal_state_vtable`. A subscriber registers a `SELECT` against one
+/// of those views; later mutations that flow through
+/// `execute_with_host_and_subscriptions` are diffed against each registered
+/// matcher and delivered as incremental `SubscriptionEvent`s instead of
+/// requiring the caller to poll.
+pub struct SubscriptionRegistry {
+    matchers: std::sync::Mutex<Vec<SubscriptionMatcher>>,
+}
+
+impl Default for SubscriptionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SubscriptionRegistry {
+    pub fn new() -> Self {
+        Self {
+            matchers: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Parses `sql` as a SELECT against a state view, records the constant
+    /// identity-column predicates it carries, and registers it under
+    /// `subscription_id`.
+    pub fn subscribe(&self, subscription_id: impl Into<String>, sql: &str) -> Result<(), EngineError> {
+        let predicate = parse_subscription_predicate(sql)?;
+        self.matchers.lock().unwrap().push(SubscriptionMatcher {
+            subscription_id: subscription_id.into(),
+            predicate,
+        });
+        Ok(())
+    }
+
+    pub fn unsubscribe(&self, subscription_id: &str) {
+        self.matchers
+            .lock()
+            .unwrap()
+            .retain(|matcher| matcher.subscription_id != subscription_id);
+    }
+
+    fn matching_subscription_ids(&self, row: &MutationKeyRow) -> Vec<String> {
+        self.matchers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|matcher| matcher.predicate.matches(row))
+            .map(|matcher| matcher.subscription_id.clone())
+            .collect()
+    }
+
+    fn matching_subscription_ids_for_effect(&self, effect: &TransactionEffect) -> Vec<String> {
+        self.matchers
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|matcher| matcher.predicate.compatible_with_effect(effect))
+            .map(|matcher| matcher.subscription_id.clone())
+            .collect()
+    }
+}
+
+fn parse_subscription_predicate(sql: &str) -> Result<SubscriptionPredicate, EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::protocol_mismatch(format!("failed to parse subscription query: {error}"))
+    })?;
+
+    let statement = statements.into_iter().next().ok_or_else(|| {
+        EngineError::protocol_mismatch("subscription query must contain a SELECT statement")
+    })?;
+
+    let Statement::Query(query) = statement else {
+        return Err(EngineError::protocol_mismatch(
+            "subscription query must be a SELECT",
+        ));
+    };
+    let SetExpr::Select(select) = *query.body else {
+        return Err(EngineError::protocol_mismatch(
+            "subscription query must be a simple SELECT",
+        ));
+    };
+
+    let references_state_view = select.from.iter().any(|table_with_joins| {
+        matches!(
+            &table_with_joins.relation,
+            TableFactor::Table { name, .. } if is_subscribable_view_name(name)
+        )
+    });
+    if !references_state_view {
+        return Err(EngineError::protocol_mismatch(
+            "subscription query must select from state, state_all, or lix_internal_state_vtable",
+        ));
+    }
+
+    let mut predicate = SubscriptionPredicate::default();
+    if let Some(selection) = &select.selection {
+        collect_subscription_equalities(selection, &mut predicate);
+    }
+    Ok(predicate)
+}
+
+fn is_subscribable_view_name(name: &ObjectName) -> bool {
+    name.0
+        .last()
+        .map(|part| {
+            part.value.eq_ignore_ascii_case(STATE_VIEW)
+                || part.value.eq_ignore_ascii_case(STATE_ALL_VIEW)
+                || part.value.eq_ignore_ascii_case(INTERNAL_STATE_VTABLE)
+        })
+        .unwrap_or(false)
+}
+
+fn collect_subscription_equalities(expr: &Expr, predicate: &mut SubscriptionPredicate) {
+    match expr {
+        Expr::BinaryOp {
+            left,
+            op: sqlparser::ast::BinaryOperator::And,
+            right,
+        } => {
+            collect_subscription_equalities(left, predicate);
+            collect_subscription_equalities(right, predicate);
+        }
+        Expr::BinaryOp {
+            left,
+            op: sqlparser::ast::BinaryOperator::Eq,
+            right,
+        } => {
+            if let (Expr::Identifier(ident), Expr::Value(value)) = (left.as_ref(), right.as_ref()) {
+                assign_subscription_predicate_field(predicate, &ident.value, value);
+            } else if let (Expr::Value(value), Expr::Identifier(ident)) =
+                (left.as_ref(), right.as_ref())
+            {
+                assign_subscription_predicate_field(predicate, &ident.value, value);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn assign_subscription_predicate_field(
+    predicate: &mut SubscriptionPredicate,
+    column: &str,
+    value: &sqlparser::ast::Value,
+) {
+    let sqlparser::ast::Value::SingleQuotedString(text) = value else {
+        return;
+    };
+    let column = column.to_lowercase();
+    if column == "schema_key" {
+        predicate.schema_key = Some(text.clone());
+    } else if column == "file_id" {
+        predicate.file_id = Some(text.clone());
+    } else if column == "entity_id" {
+        predicate.entity_id = Some(text.clone());
+    } else if column == "version_id" {
+        predicate.version_id = Some(text.clone());
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MutationKeyRow {
+    entity_id: String,
+    schema_key: String,
+    file_id: String,
+    version_id: Option<String>,
+    snapshot_content: Value,
+}
+
+/// Extracts the identity columns plus snapshot content from each row of an
+/// `INSERT INTO state[_all]` statement, reusing the same column-resolution
+/// and literal-evaluation approach as `extract_insert_validation_rows`.
+fn extract_mutation_key_rows(
+    statement: &Statement,
+    params: &[Value],
+    param_cursor: &mut usize,
+) -> Result<Vec<MutationKeyRow>, EngineError> {
+    let Statement::Insert(insert) = statement else {
+        return Ok(Vec::new());
+    };
+    if !is_validation_target_name(&insert.table_name) {
+        return Ok(Vec::new());
+    }
+    let Some(source) = &insert.source else {
+        return Ok(Vec::new());
+    };
+    let SetExpr::Values(values) = &*source.body else {
+        return Ok(Vec::new());
+    };
+
+    let column_names: Vec<String> = if insert.columns.is_empty() {
+        vec![
+            "entity_id".to_owned(),
+            "schema_key".to_owned(),
+            "file_id".to_owned(),
+            "plugin_key".to_owned(),
+            "snapshot_content".to_owned(),
+            "schema_version".to_owned(),
+            "metadata".to_owned(),
+            "untracked".to_owned(),
+            "version_id".to_owned(),
+        ]
+    } else {
+        insert
+            .columns
+            .iter()
+            .map(|ident| ident.value.to_lowercase())
+            .collect()
+    };
+
+    let find_column = |name: &str| column_names.iter().position(|column| column == name);
+    let entity_id_idx = find_column("entity_id").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required entity_id column")
+    })?;
+    let schema_key_idx = find_column("schema_key").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required schema_key column")
+    })?;
+    let file_id_idx = find_column("file_id").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required file_id column")
+    })?;
+    let snapshot_idx = find_column("snapshot_content").ok_or_else(|| {
+        EngineError::rewrite_validation("state mutation missing required snapshot_content column")
+    })?;
+    let version_id_idx = find_column("version_id");
+
+    let mut result = Vec::with_capacity(values.rows.len());
+    for row in &values.rows {
+        if row.len() != column_names.len() {
+            return Err(EngineError::rewrite_validation(
+                "insert row shape does not match declared columns",
+            ));
+        }
+
+        let entity_id = evaluate_sql_expr_to_json(&row[entity_id_idx], params, param_cursor, false)?;
+        let schema_key = evaluate_sql_expr_to_json(&row[schema_key_idx], params, param_cursor, false)?;
+        let file_id = evaluate_sql_expr_to_json(&row[file_id_idx], params, param_cursor, false)?;
+        let snapshot_content =
+            evaluate_sql_expr_to_json(&row[snapshot_idx], params, param_cursor, true)?;
+        let version_id = match version_id_idx {
+            Some(idx) => {
+                Some(evaluate_sql_expr_to_json(&row[idx], params, param_cursor, false)?)
+            }
+            None => None,
+        };
+
+        result.push(MutationKeyRow {
+            entity_id: entity_id
+                .as_str()
+                .ok_or_else(|| EngineError::rewrite_validation("entity_id must resolve to a string"))?
+                .to_owned(),
+            schema_key: schema_key
+                .as_str()
+                .ok_or_else(|| EngineError::rewrite_validation("schema_key must resolve to a string"))?
+                .to_owned(),
+            file_id: file_id
+                .as_str()
+                .ok_or_else(|| EngineError::rewrite_validation("file_id must resolve to a string"))?
+                .to_owned(),
+            version_id: version_id.and_then(|value| value.as_str().map(str::to_owned)),
+            snapshot_content,
+        });
+    }
+
+    Ok(result)
+}
+
+/// Runs `execute_with_host`, then diffs any rows the write touched against
+/// `registry`'s matchers and delivers `SubscriptionEvent::Change` events for
+/// every match. INSERT rows are diffed with their literal `snapshot_content`;
+/// UPDATE/DELETE rows reuse `extract_update_effect_rows`/
+/// `extract_delete_effect_rows` (the same WHERE-clause extractors
+/// `validate_mutation_statements` already runs for `TransactionReport`), so
+/// they carry no `snapshot_content` of their own and are delivered with
+/// `cells: Value::Null`.
+pub fn execute_with_host_and_subscriptions(
+    host: &dyn HostCallbacks,
+    registry: &SubscriptionRegistry,
+    callbacks: &dyn SubscriptionCallbacks,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let sql = request.sql.clone();
+    let params = request.params.clone();
+    let result = execute_with_host(host, request)?;
+
+    if result.statement_kind == RUST_KIND_WRITE_REWRITE || result.statement_kind == RUST_KIND_VALIDATION {
+        notify_state_mutation_subscriptions(registry, callbacks, &sql, &params)?;
+    }
+
+    Ok(result)
+}
+
+fn notify_state_mutation_subscriptions(
+    registry: &SubscriptionRegistry,
+    callbacks: &dyn SubscriptionCallbacks,
+    sql: &str,
+    params: &[Value],
+) -> Result<(), EngineError> {
+    let dialect = SQLiteDialect {};
+    let statements = Parser::parse_sql(&dialect, sql).map_err(|error| {
+        EngineError::rewrite_validation(format!("failed to parse mutation SQL for subscriptions: {error}"))
+    })?;
+
+    let mut param_cursor: usize = 0;
+    for statement in &statements {
+        let rows = extract_mutation_key_rows(statement, params, &mut param_cursor)?;
+        for row in rows {
+            for subscription_id in registry.matching_subscription_ids(&row) {
+                callbacks.emit(
+                    &subscription_id,
+                    SubscriptionEvent::Change {
+                        kind: ChangeKind::Insert,
+                        entity_id: row.entity_id.clone(),
+                        cells: row.snapshot_content.clone(),
+                    },
+                );
+            }
+        }
+
+        if let Some(effect) = extract_update_effect_rows(statement) {
+            emit_effect_change(registry, callbacks, ChangeKind::Update, &effect);
+        }
+        if let Some(effect) = extract_delete_effect_rows(statement) {
+            emit_effect_change(registry, callbacks, ChangeKind::Delete, &effect);
+        }
+    }
+
+    Ok(())
+}
+
+fn emit_effect_change(
+    registry: &SubscriptionRegistry,
+    callbacks: &dyn SubscriptionCallbacks,
+    kind: ChangeKind,
+    effect: &TransactionEffect,
+) {
+    let Some(entity_id) = effect.entity_id.clone() else {
+        return;
+    };
+    for subscription_id in registry.matching_subscription_ids_for_effect(effect) {
+        callbacks.emit(
+            &subscription_id,
+            SubscriptionEvent::Change {
+                kind,
+                entity_id: entity_id.clone(),
+                cells: Value::Null,
+            },
+        );
+    }
+}
+
+/// Replays the current result set of `sql` through `callbacks` as
+/// `Columns`/`Row`/`EndOfQuery` events, then registers `subscription_id`
+/// against `registry` so subsequent mutations are delivered as `Change`
+/// events. This is the "subscribe" half of the live-query contract: a new
+/// subscriber sees a consistent snapshot before any deltas, rather than
+/// starting from an empty result set.
+pub fn subscribe_with_replay(
+    registry: &SubscriptionRegistry,
+    host: &dyn HostCallbacks,
+    callbacks: &dyn SubscriptionCallbacks,
+    subscription_id: impl Into<String>,
+    sql: &str,
+) -> Result<(), EngineError> {
+    let subscription_id = subscription_id.into();
+    let rewritten_sql = rewrite_sql_for_execution(sql, RUST_KIND_READ_REWRITE)?;
+    let response = host
+        .execute(HostExecuteRequest {
+            request_id: format!("subscribe-replay-{subscription_id}"),
+            sql: rewritten_sql,
+            params: Vec::new(),
+            statement_kind: RUST_KIND_READ_REWRITE,
+        })
+        .map_err(|error| map_host_error(error, LIX_RUST_SQLITE_EXECUTION))?;
+
+    callbacks.emit(
+        &subscription_id,
+        SubscriptionEvent::Columns {
+            columns: STATE_SUBSCRIPTION_REPLAY_COLUMNS.to_vec(),
+        },
+    );
+    for row in &response.rows {
+        let Some(entity_id) = row.get("entity_id").and_then(Value::as_str) else {
+            continue;
+        };
+        callbacks.emit(
+            &subscription_id,
+            SubscriptionEvent::Row {
+                entity_id: entity_id.to_owned(),
+                cells: row.clone(),
+            },
+        );
+    }
+    callbacks.emit(&subscription_id, SubscriptionEvent::EndOfQuery);
+
+    registry.subscribe(subscription_id, sql)
+}
+
+/// Receives a `TransactionReport` after each successful execute that went
+/// through `execute_with_host_and_observers`. Modeled on
+/// `SubscriptionCallbacks`, except observers are registered once (via
+/// `TransactionObserverRegistry::register`) rather than supplied per call,
+/// since a transaction report has no per-call subscription query to match
+/// against.
+pub trait TransactionObserver: Send + Sync {
+    fn on_transaction(&self, report: &TransactionReport);
+}
+
+/// Registry of `TransactionObserver`s to notify after every successful
+/// mutating execute that runs through `execute_with_host_and_observers`,
+/// enabling downstream indexing, audit logging, or cache invalidation driven
+/// by what actually changed rather than by re-parsing SQL.
+#[derive(Default)]
+pub struct TransactionObserverRegistry {
+    observers: std::sync::Mutex<Vec<std::sync::Arc<dyn TransactionObserver>>>,
+}
+
+impl TransactionObserverRegistry {
+    pub fn new() -> Self {
+        Self {
+            observers: std::sync::Mutex::new(Vec::new()),
+        }
+    }
+
+    pub fn register(&self, observer: std::sync::Arc<dyn TransactionObserver>) {
+        self.observers.lock().unwrap().push(observer);
+    }
+
+    fn notify(&self, report: &TransactionReport) {
+        for observer in self.observers.lock().unwrap().iter() {
+            observer.on_transaction(report);
+        }
+    }
+}
+
+/// Runs `execute_with_host`, then notifies every observer in `registry` with
+/// the resulting `TransactionReport` whenever the statement was a mutation
+/// that produced asserted or retracted entities.
+pub fn execute_with_host_and_observers(
+    host: &dyn HostCallbacks,
+    registry: &TransactionObserverRegistry,
+    request: ExecuteRequest,
+) -> Result<ExecuteResult, EngineError> {
+    let result = execute_with_host(host, request)?;
+    if !result.transaction_report.is_empty() {
+        registry.notify(&result.transaction_report);
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use serde_json::{json, Value};
+
+    use super::{
+        execute_with_host, execute_with_host_and_subscriptions, plan_execute,
+        rewrite_sql_for_execution, rewrite_sql_for_execution_as_of, route_statement_kind, AsOf,
+        EngineError, ExecuteRequest, HostCallbacks, HostDetectChangesRequest,
+        HostDetectChangesResponse, HostExecuteRequest, HostExecuteResponse, PluginChangeRequest,
+        LIX_RUST_DETECT_CHANGES, LIX_RUST_PROTOCOL_MISMATCH, LIX_RUST_REWRITE_VALIDATION,
+        LIX_RUST_SQLITE_EXECUTION, RUST_KIND_PASSTHROUGH, RUST_KIND_READ_REWRITE,
+        RUST_KIND_VALIDATION, RUST_KIND_WRITE_REWRITE, RUST_ROWS_AFFECTED_ROWS_LENGTH,
+        RUST_ROWS_AFFECTED_SQLITE_CHANGES, StatementResult,
+    };
+
+    #[derive(Default)]
+    struct TestHost {
+        execute_calls: RefCell<Vec<HostExecuteRequest>>,
+        detect_calls: RefCell<Vec<HostDetectChangesRequest>>,
+        execute_response: RefCell<Option<Result<HostExecuteResponse, EngineError>>>,
+        detect_response: RefCell<Option<Result<HostDetectChangesResponse, EngineError>>>,
+    }
+
+    struct ValidationHost {
+        execute_calls: RefCell<Vec<HostExecuteRequest>>,
+        schema_value: Value,
+    }
+
+    impl HostCallbacks for ValidationHost {
+        fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
+            self.execute_calls.borrow_mut().push(request.clone());
+            if request.sql.to_lowercase().contains("from stored_schema") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "value": self.schema_value.clone() })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            })
+        }
+
+        fn detect_changes(
+            &self,
+            _request: HostDetectChangesRequest,
+        ) -> Result<HostDetectChangesResponse, EngineError> {
+            Ok(HostDetectChangesResponse {
+                changes: Vec::new(),
+            })
+        }
+    }
+
+    impl HostCallbacks for TestHost {
+        fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
+            let is_schema_query = request.sql.to_lowercase().contains("from stored_schema");
+            self.execute_calls.borrow_mut().push(request);
+            if is_schema_query {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({
+                        "value": {
+                            "type": "object",
+                            "x-lix-key": "mock_schema",
+                            "x-lix-version": "1.0",
+                            "additionalProperties": true
+                        }
+                    })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            self.execute_response
+                .borrow_mut()
+                .take()
+                .unwrap_or_else(|| {
+                    Ok(HostExecuteResponse {
+                        rows: Vec::new(),
+                        rows_affected: 0,
+                        last_insert_row_id: None,
+                    })
+                })
+        }
+
+        fn detect_changes(
+            &self,
+            request: HostDetectChangesRequest,
+        ) -> Result<HostDetectChangesResponse, EngineError> {
+            self.detect_calls.borrow_mut().push(request);
+            self.detect_response.borrow_mut().take().unwrap_or_else(|| {
+                Ok(HostDetectChangesResponse {
+                    changes: Vec::new(),
+                })
+            })
+        }
+    }
+
+    #[test]
+    fn routes_reads() {
+        assert_eq!(route_statement_kind("select 1"), RUST_KIND_READ_REWRITE);
+    }
+
+    #[test]
+    fn routes_writes() {
+        assert_eq!(
+            route_statement_kind("insert into file (id) values ('x')"),
+            RUST_KIND_WRITE_REWRITE
+        );
+    }
+
+    #[test]
+    fn routes_passthrough() {
+        assert_eq!(
+            route_statement_kind("pragma user_version"),
+            RUST_KIND_PASSTHROUGH
+        );
+    }
+
+    #[test]
+    fn routes_validation_for_state_table_writes() {
+        assert_eq!(
+            route_statement_kind("insert into state (entity_id) values ('e')"),
+            RUST_KIND_VALIDATION
+        );
+        assert_eq!(
+            route_statement_kind("update state set schema_key = 'x' where entity_id = 'e'"),
+            RUST_KIND_VALIDATION
+        );
+    }
+
+    #[test]
+    fn plans_read_execution() {
+        let plan = plan_execute("select 1");
+        assert_eq!(plan.statement_kind, RUST_KIND_READ_REWRITE);
+        assert_eq!(plan.preprocess_mode, "full");
+        assert_eq!(plan.rows_affected_mode, RUST_ROWS_AFFECTED_ROWS_LENGTH);
+    }
+
+    #[test]
+    fn plans_write_and_validation_execution() {
+        let write_plan = plan_execute("insert into file (id) values ('x')");
+        assert_eq!(write_plan.statement_kind, RUST_KIND_WRITE_REWRITE);
+        assert_eq!(write_plan.preprocess_mode, "full");
+        assert_eq!(
+            write_plan.rows_affected_mode,
+            RUST_ROWS_AFFECTED_SQLITE_CHANGES
+        );
+
+        let validation_plan = plan_execute("insert into state (entity_id) values ('x')");
+        assert_eq!(validation_plan.statement_kind, RUST_KIND_VALIDATION);
+        assert_eq!(validation_plan.preprocess_mode, "full");
+        assert_eq!(
+            validation_plan.rows_affected_mode,
+            RUST_ROWS_AFFECTED_SQLITE_CHANGES
+        );
+    }
+
+    #[test]
+    fn rewrites_state_vtable_selects_to_derived_query() {
+        let rewritten = rewrite_sql_for_execution(
+            "select entity_id from lix_internal_state_vtable where schema_key = 'lix_active_version'",
+            RUST_KIND_READ_REWRITE,
+        )
+        .expect("read rewrite should succeed");
+
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("from (select"));
+        assert!(normalized.contains("from lix_internal_state_all_untracked"));
+        assert!(normalized.contains("as lix_internal_state_vtable"));
+    }
+
+    #[test]
+    fn rewrites_state_vtable_selects_with_alias() {
+        let rewritten = rewrite_sql_for_execution(
+            "select v.entity_id from lix_internal_state_vtable as v",
+            RUST_KIND_READ_REWRITE,
+        )
+        .expect("read rewrite with alias should succeed");
+
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("as v"));
+        assert!(normalized.contains("from lix_internal_state_all_untracked"));
+    }
+
+    #[test]
+    fn rewrites_state_vtable_selects_as_of_timestamp() {
+        let rewritten = rewrite_sql_for_execution_as_of(
+            "select entity_id from lix_internal_state_vtable",
+            RUST_KIND_READ_REWRITE,
+            Some(&AsOf::Timestamp("2024-01-01T00:00:00Z".to_owned())),
+        )
+        .expect("as-of read rewrite should succeed");
+
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("from lix_internal_change"));
+        assert!(normalized.contains("2024-01-01t00:00:00z"));
+        assert!(normalized.contains("as change_id"));
+        // The latest-row-per-identity subquery must break ties on the
+        // unique, monotonic rowid (as the as-of-commit branch already
+        // does), not on created_at, which two distinct changes can share
+        // and which would otherwise let both survive the join.
+        assert!(normalized.contains("max(rowid)"));
+        assert!(normalized.contains("latest.rowid = c.rowid"));
+        assert!(!normalized.contains("latest.created_at = c.created_at"));
+    }
+
+    #[test]
+    fn rewrites_state_vtable_selects_as_of_commit() {
+        let rewritten = rewrite_sql_for_execution_as_of(
+            "select entity_id from lix_internal_state_vtable",
+            RUST_KIND_READ_REWRITE,
+            Some(&AsOf::CommitId("commit-1".to_owned())),
+        )
+        .expect("as-of read rewrite should succeed");
+
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("with recursive reachable_commit"));
+        assert!(normalized.contains("'commit-1'"));
+    }
+
+    #[test]
+    fn preserves_non_vtable_read_sql() {
+        let sql = "select id, path from file order by id limit 1";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_READ_REWRITE).expect("rewrite should work");
+        assert_eq!(rewritten, sql);
+    }
+
+    #[test]
+    fn rewrites_state_insert_with_materialized_rows() {
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'k', 'f1', 'json', json('{}'), '1', json('{}'), 0), ('e2', 'k', 'f2', 'json', json('{}'), '1', json('{}'), 1)";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("with \"__lix_mutation_rows\""));
+        assert!(normalized.contains("insert into state_by_version"));
+        assert!(normalized.contains("select version_id from active_version"));
+        assert!(normalized.contains("select \"entity_id\""));
+    }
+
+    #[test]
+    fn rewrites_state_insert_with_returning_clause() {
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'k', 'f1', 'json', json('{}'), '1', json('{}'), 0) returning entity_id";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.ends_with("returning entity_id"));
+    }
+
+    #[test]
+    fn rewrites_state_delete_with_returning_clause() {
+        let sql = "delete from state where entity_id = 'e1' returning entity_id, schema_key";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_WRITE_REWRITE).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.ends_with("returning entity_id, schema_key"));
+    }
+
+    #[test]
+    fn plans_returning_insert_with_rows_length_policy() {
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'k', 'f1', 'json', json('{}'), '1', json('{}'), 0) returning entity_id";
+        let plan = plan_execute(sql);
+        assert_eq!(plan.statement_kind, RUST_KIND_VALIDATION);
+        assert_eq!(plan.rows_affected_mode, RUST_ROWS_AFFECTED_ROWS_LENGTH);
+        assert_eq!(plan.result_shape(), StatementResult::RowsReturned);
+    }
+
+    #[test]
+    fn rewrites_state_insert_on_conflict_do_nothing() {
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'k', 'f1', 'json', json('{}'), '1', json('{}'), 0) on conflict(entity_id, schema_key, file_id, version_id) do nothing";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("insert into state_by_version"));
+        assert!(normalized.contains("on conflict (\"entity_id\", \"schema_key\", \"file_id\", \"version_id\") do nothing"));
+    }
+
+    #[test]
+    fn rewrites_state_update_to_deterministic_cte() {
+        let sql = "update state set snapshot_content = json('{\"value\":2}'), untracked = 1 where schema_key = 'lix_key_value'";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("with \"__lix_mutation_rows\" as"));
+        assert!(normalized.contains("from state_by_version where (schema_key = 'lix_key_value') and (version_id in (select version_id from active_version))"));
+        assert!(normalized.contains("order by entity_id, schema_key, file_id, version_id"));
+        assert!(normalized.contains(
+            "update state_by_version set snapshot_content = json('{\"value\":2}'), untracked = 1"
+        ));
+    }
+
+    #[test]
+    fn rewrites_state_by_version_delete_to_deterministic_cte() {
+        let sql =
+            "delete from state_by_version where version_id = 'global' and schema_key = 'lix_file'";
+        let rewritten =
+            rewrite_sql_for_execution(sql, RUST_KIND_WRITE_REWRITE).expect("rewrite should work");
+        let normalized = rewritten.to_lowercase();
+        assert!(normalized.contains("with \"__lix_mutation_rows\" as"));
+        assert!(normalized.contains(
+            "from state_by_version where version_id = 'global' and schema_key = 'lix_file'"
+        ));
+        assert!(normalized.contains("order by entity_id, schema_key, file_id, version_id"));
+        assert!(normalized.contains("delete from state_by_version"));
+        assert!(normalized.contains("where (entity_id, schema_key, file_id, version_id) in"));
+    }
+
+    #[test]
+    fn executes_read_rewrite_with_rows_length_policy() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![json!({ "value": 1 })],
+                rows_affected: 99,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-read".to_owned(),
+                sql: "select 1 as value".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("read execution should succeed");
+
+        assert_eq!(result.statement_kind, RUST_KIND_READ_REWRITE);
+        assert_eq!(result.rows_affected, 1);
+        assert!(result.plugin_changes.is_empty());
+
+        let calls = host.execute_calls.borrow();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].statement_kind, RUST_KIND_READ_REWRITE);
+        assert_eq!(calls[0].sql, "select 1 as value");
+        assert!(host.detect_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn executes_write_rewrite_and_runs_plugin_change_detection() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 2,
+                last_insert_row_id: Some(10),
+            }))),
+            detect_response: RefCell::new(Some(Ok(HostDetectChangesResponse {
+                changes: vec![json!({ "type": "file_update" })],
+            }))),
+            ..Default::default()
+        };
+
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-write".to_owned(),
+                sql: "insert into file (id) values ('x')".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![1],
+                    after: vec![2],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("write execution should succeed");
+
+        assert_eq!(result.statement_kind, RUST_KIND_WRITE_REWRITE);
+        assert_eq!(result.rows_affected, 2);
+        assert_eq!(result.last_insert_row_id, Some(10));
+        assert_eq!(
+            result.plugin_changes,
+            vec![json!({ "type": "file_update" })]
+        );
+
+        assert_eq!(host.execute_calls.borrow().len(), 1);
+        assert_eq!(host.detect_calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn executes_validation_path_and_uses_sqlite_changes_policy() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![json!({ "ignored": true })],
+                rows_affected: 3,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'k', 'f', 'json', json('{}'), '1', json('{}'), 0)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-validation".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![],
+                    after: vec![],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("validation execution should succeed");
+
+        assert_eq!(result.statement_kind, RUST_KIND_VALIDATION);
+        assert_eq!(result.rows_affected, 3);
+        assert!(result.plugin_changes.is_empty());
+        assert!(host.detect_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn executes_validation_detect_changes_for_lix_file_mutations() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            }))),
+            detect_response: RefCell::new(Some(Ok(HostDetectChangesResponse {
+                changes: vec![json!({ "type": "file_state_change" })],
+            }))),
+            ..Default::default()
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values (?, ?, ?, ?, json('{}'), ?, json('{}'), 0)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-validation-file".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![
+                    json!("e"),
+                    json!("lix_file"),
+                    json!("f"),
+                    json!("json"),
+                    json!("1"),
+                ],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![],
+                    after: vec![],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("validation execution should succeed");
+
+        assert_eq!(
+            result.plugin_changes,
+            vec![json!({ "type": "file_state_change" })]
+        );
+        assert_eq!(host.detect_calls.borrow().len(), 1);
+    }
+
+    #[test]
+    fn does_not_detect_changes_when_lix_file_only_appears_in_unrelated_column() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'lix_key_value', 'f', 'json', json('{}'), '1', json('{\"note\":\"lix_file\"}'), 0)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-not-file".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![],
+                    after: vec![],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("validation execution should succeed");
+
+        assert!(result.plugin_changes.is_empty());
+        assert!(host.detect_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn executes_passthrough_without_rewrite_or_detect_changes() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![json!({ "user_version": 7 })],
+                rows_affected: 42,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-pass".to_owned(),
+                sql: "pragma user_version".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![],
+                    after: vec![],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("passthrough execution should succeed");
+
+        assert_eq!(result.statement_kind, RUST_KIND_PASSTHROUGH);
+        assert_eq!(result.rows_affected, 1);
+        assert!(result.plugin_changes.is_empty());
+        assert_eq!(host.execute_calls.borrow().len(), 1);
+        assert!(host.detect_calls.borrow().is_empty());
+    }
+
+    #[test]
+    fn maps_execute_failures_to_stable_sqlite_error_code() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Err(EngineError::new(
+                "UNCLASSIFIED",
+                "SQLITE_ERROR: no such table: missing",
+            )))),
+            ..Default::default()
+        };
+
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-error".to_owned(),
+                sql: "select * from missing".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("execution should fail");
+
+        assert_eq!(error.code, LIX_RUST_SQLITE_EXECUTION);
+    }
+
+    #[test]
+    fn maps_detect_changes_failures_to_stable_error_code() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            }))),
+            detect_response: RefCell::new(Some(Err(EngineError::new(
+                "UNCLASSIFIED",
+                "plugin failed",
+            )))),
+            ..Default::default()
+        };
+
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-detect-error".to_owned(),
+                sql: "insert into file (id) values ('x')".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![PluginChangeRequest {
+                    plugin_key: "json".to_owned(),
+                    before: vec![],
+                    after: vec![],
+                }],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("detect changes should fail");
+
+        assert_eq!(error.code, LIX_RUST_DETECT_CHANGES);
+    }
+
+    #[test]
+    fn returns_validation_error_for_non_state_validation_mutation() {
+        let host = TestHost::default();
+
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-invalid-validation".to_owned(),
+                sql: "update stateful set schema_key = 'x' where entity_id = 'e'".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("invalid validation target should fail");
+
+        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+    }
+
+    #[test]
+    fn returns_validation_error_for_snapshot_schema_violation() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "name": { "type": "string" }
+            },
+            "required": ["name"],
+            "additionalProperties": false
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-schema-invalid".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("invalid snapshot should fail validation");
+
+        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+    }
+
+    #[test]
+    fn returns_validation_error_for_invalid_cel_in_schema() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "name": {
+                    "type": "string",
+                    "x-lix-default": "1 +"
+                }
+            },
+            "additionalProperties": false
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"name\":\"ok\"}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-invalid".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("invalid CEL expression should fail validation");
+
+        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+    }
+
+    #[test]
+    fn accepts_snapshot_satisfying_cel_constraints() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "integer" }
+            },
+            "required": ["count"],
+            "additionalProperties": false,
+            "x-lix-constraints": ["count > 0"]
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-constraint-pass".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("snapshot satisfying x-lix-constraints should be accepted");
+    }
+
+    #[test]
+    fn returns_validation_error_for_violated_cel_constraint() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "integer" }
+            },
+            "required": ["count"],
+            "additionalProperties": false,
+            "x-lix-constraints": ["count > 0"]
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":0}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-constraint-fail".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("snapshot violating x-lix-constraints should fail validation");
+
+        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+    }
+
+    #[test]
+    fn materializes_cel_default_for_missing_snapshot_property() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "integer" },
+                "status": { "type": "string", "x-lix-default": "'draft'" }
+            },
+            "required": ["count"],
+            "additionalProperties": false
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-default".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("missing optional property with an x-lix-default should materialize");
+
+        let executed_sql = host
+            .execute_calls
+            .borrow()
+            .last()
+            .expect("physical insert should have been executed")
+            .sql
+            .clone();
+        assert!(executed_sql.contains("\"status\":\"draft\""));
+    }
+
+    #[test]
+    fn materializes_cel_default_before_validating_a_required_property() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "integer" },
+                "status": { "type": "string", "x-lix-default": "'draft'" }
+            },
+            "required": ["count", "status"],
+            "additionalProperties": false
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
+        };
+
+        // `status` is both `required` and supplied only via `x-lix-default`.
+        // Validation must see the defaulted snapshot, not the raw one the
+        // row arrived with, or this would fail as `missing_required` even
+        // though the default satisfies it.
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-default-required".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("a required property satisfied only via x-lix-default should validate and materialize");
+
+        let executed_sql = host
+            .execute_calls
+            .borrow()
+            .last()
+            .expect("physical insert should have been executed")
+            .sql
+            .clone();
+        assert!(executed_sql.contains("\"status\":\"draft\""));
+    }
 
-    if target == WriteTarget::State {
-        return match selection_sql {
-            Some(sql) => Some(format!("({sql}) AND ({active_version_filter})")),
-            None => Some(active_version_filter.to_owned()),
+    #[test]
+    fn materializes_cel_override_for_lix_columns() {
+        let schema = json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "integer" }
+            },
+            "required": ["count"],
+            "additionalProperties": false,
+            "x-lix-override-lixcols": {
+                "plugin_key": "'forced_plugin'"
+            }
+        });
+        let host = ValidationHost {
+            execute_calls: RefCell::new(Vec::new()),
+            schema_value: schema,
         };
-    }
 
-    selection_sql
-}
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'caller_plugin', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-cel-override".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("x-lix-override-lixcols should materialize");
+
+        let executed_sql = host
+            .execute_calls
+            .borrow()
+            .last()
+            .expect("physical insert should have been executed")
+            .sql
+            .clone();
+        assert!(executed_sql.contains("'forced_plugin'"));
+        assert!(!executed_sql.contains("'caller_plugin'"));
+    }
 
-fn quote_ident(ident: &str) -> String {
-    format!("\"{}\"", ident.replace('"', "\"\""))
-}
+    struct UpsertHost {
+        schema_value: Value,
+        existing_snapshot: Option<Value>,
+        execute_calls: RefCell<Vec<HostExecuteRequest>>,
+    }
 
-fn execute_plugin_change_detection(
-    host: &dyn HostCallbacks,
-    request_id: &str,
-    requests: &[PluginChangeRequest],
-) -> Result<Vec<Value>, EngineError> {
-    let mut all_changes = Vec::new();
+    impl HostCallbacks for UpsertHost {
+        fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
+            self.execute_calls.borrow_mut().push(request.clone());
+            let lowered = request.sql.to_lowercase();
+            if lowered.contains("from stored_schema") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "value": self.schema_value.clone() })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            if lowered.contains("from active_version") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "version_id": "mock-active-version" })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            if lowered.contains("from state_by_version") {
+                let rows = match &self.existing_snapshot {
+                    Some(snapshot) => vec![json!({ "snapshot_content": snapshot })],
+                    None => vec![],
+                };
+                return Ok(HostExecuteResponse {
+                    rows,
+                    rows_affected: 0,
+                    last_insert_row_id: None,
+                });
+            }
+            Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            })
+        }
 
-    for request in requests {
-        let response = host
-            .detect_changes(HostDetectChangesRequest {
-                request_id: request_id.to_owned(),
-                plugin_key: request.plugin_key.clone(),
-                before: request.before.clone(),
-                after: request.after.clone(),
+        fn detect_changes(
+            &self,
+            _request: HostDetectChangesRequest,
+        ) -> Result<HostDetectChangesResponse, EngineError> {
+            Ok(HostDetectChangesResponse {
+                changes: Vec::new(),
             })
-            .map_err(|error| map_host_error(error, LIX_RUST_DETECT_CHANGES))?;
+        }
+    }
 
-        all_changes.extend(response.changes);
+    fn upsert_schema() -> Value {
+        json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "additionalProperties": true
+        })
     }
 
-    Ok(all_changes)
-}
+    #[test]
+    fn upsert_reports_created_when_no_conflicting_row_exists() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: None,
+            execute_calls: RefCell::new(Vec::new()),
+        };
 
-fn should_run_plugin_change_detection(statement_kind: &str, sql: &str, params: &[Value]) -> bool {
-    if statement_kind != RUST_KIND_WRITE_REWRITE && statement_kind != RUST_KIND_VALIDATION {
-        return false;
-    }
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do update set snapshot_content = excluded.snapshot_content";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-upsert-created".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("upsert with no existing row should succeed");
 
-    let lowered = sql.to_lowercase();
-    if lowered.contains("insert into file")
-        || lowered.contains("update file")
-        || lowered.contains("delete from file")
-    {
-        return true;
+        assert_eq!(result.upsert_resolutions.len(), 1);
+        assert_eq!(result.upsert_resolutions[0].outcome, UpsertOutcome::Created);
     }
 
-    let mutates_state = lowered.contains("insert into state")
-        || lowered.contains("insert into state_by_version")
-        || lowered.contains("insert into lix_internal_state_vtable")
-        || lowered.contains("update state")
-        || lowered.contains("update state_by_version")
-        || lowered.contains("update lix_internal_state_vtable")
-        || lowered.contains("delete from state")
-        || lowered.contains("delete from state_by_version")
-        || lowered.contains("delete from lix_internal_state_vtable");
-    if !mutates_state {
-        return false;
+    fn nested_required_schema() -> Value {
+        json!({
+            "type": "object",
+            "x-lix-key": "mock_schema",
+            "x-lix-version": "1.0",
+            "properties": {
+                "count": { "type": "number" },
+                "nested": {
+                    "type": "object",
+                    "properties": { "a": { "type": "number" }, "b": { "type": "number" } },
+                    "required": ["a"]
+                }
+            },
+            "required": ["count", "nested"],
+            "additionalProperties": true
+        })
     }
 
-    if lowered.contains("lix_file") {
-        return true;
-    }
+    #[test]
+    fn upsert_json_patch_deep_merges_and_keeps_fields_only_the_existing_row_has() {
+        let host = UpsertHost {
+            schema_value: nested_required_schema(),
+            existing_snapshot: Some(json!({ "count": 1, "nested": { "a": 1, "b": 2 } })),
+            execute_calls: RefCell::new(Vec::new()),
+        };
 
-    params.iter().any(|value| match value {
-        Value::String(text) => text == "lix_file",
-        _ => false,
-    })
-}
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":2,\"nested\":{\"b\":3}}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do update set snapshot_content = json_patch(snapshot_content, excluded.snapshot_content)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-upsert-merge".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        );
 
-fn map_host_error(error: EngineError, default_code: &'static str) -> EngineError {
-    if error.code == LIX_RUST_SQLITE_EXECUTION
-        || error.code == LIX_RUST_DETECT_CHANGES
-        || error.code == LIX_RUST_REWRITE_VALIDATION
-        || error.code == LIX_RUST_UNSUPPORTED_SQLITE_FEATURE
-        || error.code == LIX_RUST_PROTOCOL_MISMATCH
-        || error.code == LIX_RUST_TIMEOUT
-        || error.code == LIX_RUST_UNKNOWN
-    {
-        return error;
+        // A `json_patch` assignment is a genuine RFC 7386 deep merge, so
+        // `nested.a` (only present in the existing row) survives the merge
+        // and the still-required property is satisfied.
+        let result = result.expect("deep merge should keep required nested.a and pass validation");
+        assert_eq!(result.upsert_resolutions.len(), 1);
+        assert_eq!(result.upsert_resolutions[0].outcome, UpsertOutcome::Updated);
+
+        // The persisted INSERT must write the same merged row validation
+        // checked, not ask SQLite to run `json_patch` again: the merged
+        // snapshot is baked into the VALUES row and the conflicting
+        // assignment is reduced to a plain `excluded` reference.
+        let calls = host.execute_calls.borrow();
+        let write_call = calls
+            .iter()
+            .find(|call| call.sql.contains(super::MUTATION_ROW_CTE))
+            .expect("rewritten insert should be executed");
+        assert!(write_call.sql.contains("\"a\":1"));
+        assert!(write_call.sql.contains("\"b\":3"));
+        assert!(!write_call.sql.contains("json_patch"));
+        assert!(write_call.sql.contains("excluded.\"snapshot_content\""));
     }
 
-    EngineError::new(default_code, error.message)
-}
-
-#[cfg(test)]
-mod tests {
-    use std::cell::RefCell;
-
-    use serde_json::{json, Value};
+    #[test]
+    fn upsert_explicit_shallow_merge_marker_replaces_nested_objects_wholesale() {
+        let host = UpsertHost {
+            schema_value: nested_required_schema(),
+            existing_snapshot: Some(json!({ "count": 1, "nested": { "a": 1, "b": 2 } })),
+            execute_calls: RefCell::new(Vec::new()),
+        };
 
-    use super::{
-        execute_with_host, plan_execute, rewrite_sql_for_execution, route_statement_kind,
-        EngineError, ExecuteRequest, HostCallbacks, HostDetectChangesRequest,
-        HostDetectChangesResponse, HostExecuteRequest, HostExecuteResponse, PluginChangeRequest,
-        LIX_RUST_DETECT_CHANGES, LIX_RUST_REWRITE_VALIDATION, LIX_RUST_SQLITE_EXECUTION,
-        RUST_KIND_PASSTHROUGH, RUST_KIND_READ_REWRITE, RUST_KIND_VALIDATION,
-        RUST_KIND_WRITE_REWRITE, RUST_ROWS_AFFECTED_ROWS_LENGTH, RUST_ROWS_AFFECTED_SQLITE_CHANGES,
-    };
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":2,\"nested\":{\"b\":3}}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do update set snapshot_content = lix_shallow_merge(snapshot_content, excluded.snapshot_content)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-upsert-shallow-merge".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        );
 
-    #[derive(Default)]
-    struct TestHost {
-        execute_calls: RefCell<Vec<HostExecuteRequest>>,
-        detect_calls: RefCell<Vec<HostDetectChangesRequest>>,
-        execute_response: RefCell<Option<Result<HostExecuteResponse, EngineError>>>,
-        detect_response: RefCell<Option<Result<HostDetectChangesResponse, EngineError>>>,
+        // The engine's own shallow-merge marker replaces `nested` one level
+        // deep, dropping `nested.a` — which the schema still requires, so
+        // this fails validation instead of silently persisting a bad row.
+        let error = result.expect_err("shallow merge should drop required nested.a");
+        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+        assert!(error.issues.iter().any(|issue| issue.reason == "missing_required"));
     }
 
-    struct ValidationHost {
-        execute_calls: RefCell<Vec<HostExecuteRequest>>,
+    /// Unlike `UpsertHost`, this mock actually inspects the bound params of
+    /// the conflict-lookup query instead of returning `existing_snapshot`
+    /// unconditionally — so it only reports a match when the engine resolved
+    /// `version_id` to the active version before looking the row up.
+    struct ActiveVersionCheckingUpsertHost {
         schema_value: Value,
+        active_version_id: String,
+        existing_snapshot: Value,
+        execute_calls: RefCell<Vec<HostExecuteRequest>>,
     }
 
-    impl HostCallbacks for ValidationHost {
+    impl HostCallbacks for ActiveVersionCheckingUpsertHost {
         fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
             self.execute_calls.borrow_mut().push(request.clone());
-            if request.sql.to_lowercase().contains("from stored_schema") {
+            let lowered = request.sql.to_lowercase();
+            if lowered.contains("from stored_schema") {
                 return Ok(HostExecuteResponse {
                     rows: vec![json!({ "value": self.schema_value.clone() })],
                     rows_affected: 1,
                     last_insert_row_id: None,
                 });
             }
+            if lowered.contains("from active_version") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "version_id": self.active_version_id.clone() })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            if lowered.contains("from state_by_version") {
+                let bound_version_id = request.params.get(3).and_then(Value::as_str);
+                let rows = if bound_version_id == Some(self.active_version_id.as_str()) {
+                    vec![json!({ "snapshot_content": self.existing_snapshot.clone() })]
+                } else {
+                    vec![]
+                };
+                return Ok(HostExecuteResponse {
+                    rows,
+                    rows_affected: 0,
+                    last_insert_row_id: None,
+                });
+            }
             Ok(HostExecuteResponse {
                 rows: vec![],
                 rows_affected: 1,
@@ -1264,507 +5555,938 @@ mod tests {
         }
     }
 
-    impl HostCallbacks for TestHost {
-        fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
-            let is_schema_query = request.sql.to_lowercase().contains("from stored_schema");
-            self.execute_calls.borrow_mut().push(request);
-            if is_schema_query {
-                return Ok(HostExecuteResponse {
-                    rows: vec![json!({
-                        "value": {
-                            "type": "object",
-                            "x-lix-key": "mock_schema",
-                            "x-lix-version": "1.0",
-                            "additionalProperties": true
-                        }
-                    })],
-                    rows_affected: 1,
-                    last_insert_row_id: None,
-                });
-            }
-            self.execute_response
-                .borrow_mut()
-                .take()
-                .unwrap_or_else(|| {
-                    Ok(HostExecuteResponse {
-                        rows: Vec::new(),
-                        rows_affected: 0,
-                        last_insert_row_id: None,
-                    })
-                })
-        }
+    #[test]
+    fn upsert_into_state_resolves_conflict_against_the_active_version() {
+        let host = ActiveVersionCheckingUpsertHost {
+            schema_value: upsert_schema(),
+            active_version_id: "active-v1".to_owned(),
+            existing_snapshot: json!({ "count": 1 }),
+            execute_calls: RefCell::new(Vec::new()),
+        };
 
-        fn detect_changes(
-            &self,
-            request: HostDetectChangesRequest,
-        ) -> Result<HostDetectChangesResponse, EngineError> {
-            self.detect_calls.borrow_mut().push(request);
-            self.detect_response.borrow_mut().take().unwrap_or_else(|| {
-                Ok(HostDetectChangesResponse {
-                    changes: Vec::new(),
-                })
-            })
-        }
+        // `state` never carries a `version_id` column, so the conflict
+        // lookup must resolve it from `active_version` before matching the
+        // existing row. A mock that bound `version_id IS NULL` here would
+        // never find the row and would misreport `Created`.
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":2}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do update set snapshot_content = excluded.snapshot_content";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-upsert-active-version".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("upsert against state should resolve against the active version");
+
+        assert_eq!(result.upsert_resolutions.len(), 1);
+        assert_eq!(result.upsert_resolutions[0].outcome, UpsertOutcome::Updated);
+    }
+
+    #[test]
+    fn upsert_into_state_persists_the_snapshot_merged_against_the_active_version_row() {
+        let host = ActiveVersionCheckingUpsertHost {
+            schema_value: nested_required_schema(),
+            active_version_id: "active-v1".to_owned(),
+            existing_snapshot: json!({ "count": 1, "nested": { "a": 1, "b": 2 } }),
+            execute_calls: RefCell::new(Vec::new()),
+        };
+
+        // Same `state`-targeted deep merge as
+        // `upsert_json_patch_deep_merges_and_keeps_fields_only_the_existing_row_has`,
+        // but against a host that only returns the existing row once
+        // version_id has actually been resolved to the active version —
+        // proving the physical INSERT persists the correctly merged
+        // snapshot rather than the unmerged incoming one.
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":2,\"nested\":{\"b\":3}}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do update set snapshot_content = json_patch(snapshot_content, excluded.snapshot_content)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-upsert-active-version-merge".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("deep merge against the active version row should pass validation");
+
+        assert_eq!(result.upsert_resolutions[0].outcome, UpsertOutcome::Updated);
+
+        let calls = host.execute_calls.borrow();
+        let write_call = calls
+            .iter()
+            .find(|call| call.sql.contains(super::MUTATION_ROW_CTE))
+            .expect("rewritten insert should be executed");
+        assert!(write_call.sql.contains("\"a\":1"));
+        assert!(write_call.sql.contains("\"b\":3"));
+        assert!(!write_call.sql.contains("json_patch"));
+        assert!(write_call.sql.contains("excluded.\"snapshot_content\""));
     }
 
+    use super::{
+        execute_with_host_and_observers, TransactionObserver, TransactionObserverRegistry,
+        TransactionReport,
+    };
+
     #[test]
-    fn routes_reads() {
-        assert_eq!(route_statement_kind("select 1"), RUST_KIND_READ_REWRITE);
+    fn insert_produces_asserted_transaction_effect_with_schema() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: None,
+            execute_calls: RefCell::new(Vec::new()),
+        };
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-transaction-insert".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("insert should succeed");
+
+        assert_eq!(result.transaction_report.asserted.len(), 1);
+        assert!(result.transaction_report.retracted.is_empty());
+        let effect = &result.transaction_report.asserted[0];
+        assert_eq!(effect.entity_id.as_deref(), Some("e"));
+        assert_eq!(effect.schema_key.as_deref(), Some("mock_schema"));
+        assert!(effect.schema.is_some());
     }
 
     #[test]
-    fn routes_writes() {
-        assert_eq!(
-            route_statement_kind("insert into file (id) values ('x')"),
-            RUST_KIND_WRITE_REWRITE
-        );
+    fn delete_produces_retracted_transaction_effect() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: None,
+            execute_calls: RefCell::new(Vec::new()),
+        };
+
+        let sql = "delete from state where entity_id = 'e' and schema_key = 'mock_schema'";
+        let result = execute_with_host(
+            &host,
+            ExecuteRequest {
+                request_id: "req-transaction-delete".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("delete should succeed");
+
+        assert!(result.transaction_report.asserted.is_empty());
+        assert_eq!(result.transaction_report.retracted.len(), 1);
+        let effect = &result.transaction_report.retracted[0];
+        assert_eq!(effect.entity_id.as_deref(), Some("e"));
+        assert_eq!(effect.schema_key.as_deref(), Some("mock_schema"));
+        assert!(effect.schema.is_none());
     }
 
-    #[test]
-    fn routes_passthrough() {
-        assert_eq!(
-            route_statement_kind("pragma user_version"),
-            RUST_KIND_PASSTHROUGH
-        );
+    #[derive(Default)]
+    struct RecordingTransactionObserver {
+        reports: std::sync::Mutex<Vec<TransactionReport>>,
     }
 
-    #[test]
-    fn routes_validation_for_state_table_writes() {
-        assert_eq!(
-            route_statement_kind("insert into state (entity_id) values ('e')"),
-            RUST_KIND_VALIDATION
-        );
-        assert_eq!(
-            route_statement_kind("update state set schema_key = 'x' where entity_id = 'e'"),
-            RUST_KIND_VALIDATION
-        );
+    impl TransactionObserver for RecordingTransactionObserver {
+        fn on_transaction(&self, report: &TransactionReport) {
+            self.reports.lock().unwrap().push(report.clone());
+        }
     }
 
     #[test]
-    fn plans_read_execution() {
-        let plan = plan_execute("select 1");
-        assert_eq!(plan.statement_kind, RUST_KIND_READ_REWRITE);
-        assert_eq!(plan.preprocess_mode, "full");
-        assert_eq!(plan.rows_affected_mode, RUST_ROWS_AFFECTED_ROWS_LENGTH);
+    fn execute_with_host_and_observers_notifies_registered_observers() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: None,
+            execute_calls: RefCell::new(Vec::new()),
+        };
+
+        let registry = TransactionObserverRegistry::new();
+        let observer = std::sync::Arc::new(RecordingTransactionObserver::default());
+        registry.register(observer.clone());
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host_and_observers(
+            &host,
+            &registry,
+            ExecuteRequest {
+                request_id: "req-transaction-observer".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("execution with observers should succeed");
+
+        let reports = observer.reports.lock().unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].asserted.len(), 1);
     }
 
+    use super::{
+        execute_with_host_and_plan_cache, execute_with_host_cached, plan_execute_cached,
+        route_statement_kind_cached, rewrite_sql_for_execution_cached, subscribe_with_replay,
+        ChangeKind, ParseCache, QueryPlanCache, SubscriptionCallbacks, SubscriptionEvent,
+        SubscriptionRegistry, UpsertOutcome,
+    };
+
     #[test]
-    fn plans_write_and_validation_execution() {
-        let write_plan = plan_execute("insert into file (id) values ('x')");
-        assert_eq!(write_plan.statement_kind, RUST_KIND_WRITE_REWRITE);
-        assert_eq!(write_plan.preprocess_mode, "full");
+    fn cached_routing_matches_uncached_routing() {
+        let cache = ParseCache::with_capacity(8);
         assert_eq!(
-            write_plan.rows_affected_mode,
-            RUST_ROWS_AFFECTED_SQLITE_CHANGES
+            route_statement_kind_cached(&cache, "select 1"),
+            RUST_KIND_READ_REWRITE
         );
-
-        let validation_plan = plan_execute("insert into state (entity_id) values ('x')");
-        assert_eq!(validation_plan.statement_kind, RUST_KIND_VALIDATION);
-        assert_eq!(validation_plan.preprocess_mode, "full");
+        // second call with different whitespace hits the same cache entry
         assert_eq!(
-            validation_plan.rows_affected_mode,
-            RUST_ROWS_AFFECTED_SQLITE_CHANGES
+            route_statement_kind_cached(&cache, "select   1"),
+            RUST_KIND_READ_REWRITE
         );
     }
 
     #[test]
-    fn rewrites_state_vtable_selects_to_derived_query() {
-        let rewritten = rewrite_sql_for_execution(
-            "select entity_id from lix_internal_state_vtable where schema_key = 'lix_active_version'",
-            RUST_KIND_READ_REWRITE,
-        )
-        .expect("read rewrite should succeed");
-
-        let normalized = rewritten.to_lowercase();
-        assert!(normalized.contains("from (select"));
-        assert!(normalized.contains("from lix_internal_state_all_untracked"));
-        assert!(normalized.contains("as lix_internal_state_vtable"));
-    }
-
-    #[test]
-    fn rewrites_state_vtable_selects_with_alias() {
-        let rewritten = rewrite_sql_for_execution(
-            "select v.entity_id from lix_internal_state_vtable as v",
-            RUST_KIND_READ_REWRITE,
-        )
-        .expect("read rewrite with alias should succeed");
+    fn cached_plan_and_rewrite_match_uncached_results() {
+        let cache = ParseCache::with_capacity(8);
+        let sql = "select entity_id from lix_internal_state_vtable";
+        let plan = plan_execute_cached(&cache, sql);
+        assert_eq!(plan.statement_kind, RUST_KIND_READ_REWRITE);
 
-        let normalized = rewritten.to_lowercase();
-        assert!(normalized.contains("as v"));
-        assert!(normalized.contains("from lix_internal_state_all_untracked"));
+        let rewritten = rewrite_sql_for_execution_cached(&cache, sql, plan.statement_kind)
+            .expect("cached rewrite should succeed");
+        let uncached = rewrite_sql_for_execution(sql, plan.statement_kind)
+            .expect("uncached rewrite should succeed");
+        assert_eq!(rewritten, uncached);
     }
 
     #[test]
-    fn preserves_non_vtable_read_sql() {
-        let sql = "select id, path from file order by id limit 1";
-        let rewritten =
-            rewrite_sql_for_execution(sql, RUST_KIND_READ_REWRITE).expect("rewrite should work");
-        assert_eq!(rewritten, sql);
-    }
+    fn execute_with_host_cached_matches_execute_with_host() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![json!({ "value": 1 })],
+                rows_affected: 0,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+        let cache = ParseCache::with_capacity(8);
 
-    #[test]
-    fn rewrites_state_insert_with_materialized_rows() {
-        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'k', 'f1', 'json', json('{}'), '1', json('{}'), 0), ('e2', 'k', 'f2', 'json', json('{}'), '1', json('{}'), 1)";
-        let rewritten =
-            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
-        let normalized = rewritten.to_lowercase();
-        assert!(normalized.contains("with \"__lix_mutation_rows\""));
-        assert!(normalized.contains("insert into state_by_version"));
-        assert!(normalized.contains("select version_id from active_version"));
-        assert!(normalized.contains("select \"entity_id\""));
-    }
+        let result = execute_with_host_cached(
+            &cache,
+            &host,
+            ExecuteRequest {
+                request_id: "req-cached".to_owned(),
+                sql: "select 1 as value".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("cached execution should succeed");
 
-    #[test]
-    fn rewrites_state_update_to_deterministic_cte() {
-        let sql = "update state set snapshot_content = json('{\"value\":2}'), untracked = 1 where schema_key = 'lix_key_value'";
-        let rewritten =
-            rewrite_sql_for_execution(sql, RUST_KIND_VALIDATION).expect("rewrite should work");
-        let normalized = rewritten.to_lowercase();
-        assert!(normalized.contains("with \"__lix_mutation_rows\" as"));
-        assert!(normalized.contains("from state_by_version where (schema_key = 'lix_key_value') and (version_id in (select version_id from active_version))"));
-        assert!(normalized.contains("order by entity_id, schema_key, file_id, version_id"));
-        assert!(normalized.contains(
-            "update state_by_version set snapshot_content = json('{\"value\":2}'), untracked = 1"
-        ));
+        assert_eq!(result.statement_kind, RUST_KIND_READ_REWRITE);
+        assert_eq!(result.rows_affected, 1);
     }
 
     #[test]
-    fn rewrites_state_by_version_delete_to_deterministic_cte() {
-        let sql =
-            "delete from state_by_version where version_id = 'global' and schema_key = 'lix_file'";
-        let rewritten =
-            rewrite_sql_for_execution(sql, RUST_KIND_WRITE_REWRITE).expect("rewrite should work");
-        let normalized = rewritten.to_lowercase();
-        assert!(normalized.contains("with \"__lix_mutation_rows\" as"));
-        assert!(normalized.contains(
-            "from state_by_version where version_id = 'global' and schema_key = 'lix_file'"
-        ));
-        assert!(normalized.contains("order by entity_id, schema_key, file_id, version_id"));
-        assert!(normalized.contains("delete from state_by_version"));
-        assert!(normalized.contains("where (entity_id, schema_key, file_id, version_id) in"));
+    fn query_plan_cache_collapses_differently_parameterized_inserts() {
+        let cache = QueryPlanCache::with_capacity(8);
+        let insert_a = "insert into lix_key_value (key, value) values ('a', 'one')";
+        let insert_b = "insert into lix_key_value (key, value) values ('b', 'two')";
+
+        let (key_a, _) = cache
+            .allocate(insert_a, None)
+            .expect("allocate should succeed");
+        let (key_b, _) = cache
+            .allocate(insert_b, None)
+            .expect("allocate should succeed");
+
+        assert_eq!(key_a, key_b);
     }
 
     #[test]
-    fn executes_read_rewrite_with_rows_length_policy() {
+    fn execute_with_host_and_plan_cache_matches_execute_with_host() {
         let host = TestHost {
             execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
                 rows: vec![json!({ "value": 1 })],
-                rows_affected: 99,
+                rows_affected: 0,
                 last_insert_row_id: None,
             }))),
             ..Default::default()
         };
+        let plan_cache = QueryPlanCache::with_capacity(8);
 
-        let result = execute_with_host(
+        let result = execute_with_host_and_plan_cache(
+            &plan_cache,
             &host,
             ExecuteRequest {
-                request_id: "req-read".to_owned(),
+                request_id: "req-plan-cached".to_owned(),
                 sql: "select 1 as value".to_owned(),
                 params: vec![],
                 plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect("read execution should succeed");
+        .expect("plan-cached execution should succeed");
 
         assert_eq!(result.statement_kind, RUST_KIND_READ_REWRITE);
         assert_eq!(result.rows_affected, 1);
-        assert!(result.plugin_changes.is_empty());
+    }
+
+    #[test]
+    fn execute_with_host_and_plan_cache_rebinds_literals_on_cache_hit() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+        let plan_cache = QueryPlanCache::with_capacity(8);
+
+        execute_with_host_and_plan_cache(
+            &plan_cache,
+            &host,
+            ExecuteRequest {
+                request_id: "req-plan-cached-1".to_owned(),
+                sql: "insert into lix_key_value (key, value) values ('a', 'one')".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("first plan-cached execution should succeed");
+        *host.execute_response.borrow_mut() = Some(Ok(HostExecuteResponse {
+            rows: vec![],
+            rows_affected: 1,
+            last_insert_row_id: None,
+        }));
+
+        let second = execute_with_host_and_plan_cache(
+            &plan_cache,
+            &host,
+            ExecuteRequest {
+                request_id: "req-plan-cached-2".to_owned(),
+                sql: "insert into lix_key_value (key, value) values ('b', 'two')".to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("second plan-cached execution should succeed");
 
+        assert_eq!(second.rows_affected, 1);
         let calls = host.execute_calls.borrow();
-        assert_eq!(calls.len(), 1);
-        assert_eq!(calls[0].statement_kind, RUST_KIND_READ_REWRITE);
-        assert_eq!(calls[0].sql, "select 1 as value");
-        assert!(host.detect_calls.borrow().is_empty());
+        assert_eq!(calls.len(), 2);
+        assert_eq!(calls[1].params, vec![json!("b"), json!("two")]);
+    }
+
+    #[derive(Default)]
+    struct RecordingSubscriber {
+        events: RefCell<Vec<(String, SubscriptionEvent)>>,
+    }
+
+    impl SubscriptionCallbacks for RecordingSubscriber {
+        fn emit(&self, subscription_id: &str, event: SubscriptionEvent) {
+            self.events
+                .borrow_mut()
+                .push((subscription_id.to_owned(), event));
+        }
     }
 
     #[test]
-    fn executes_write_rewrite_and_runs_plugin_change_detection() {
+    fn subscribe_rejects_queries_not_over_a_state_view() {
+        let registry = SubscriptionRegistry::new();
+        let error = registry
+            .subscribe("sub-1", "select * from file")
+            .expect_err("non-state-view subscription should fail");
+        assert_eq!(error.code, LIX_RUST_PROTOCOL_MISMATCH);
+    }
+
+    #[test]
+    fn subscription_receives_change_event_for_matching_insert() {
         let host = TestHost {
             execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
                 rows: vec![],
-                rows_affected: 2,
-                last_insert_row_id: Some(10),
-            }))),
-            detect_response: RefCell::new(Some(Ok(HostDetectChangesResponse {
-                changes: vec![json!({ "type": "file_update" })],
+                rows_affected: 1,
+                last_insert_row_id: None,
             }))),
             ..Default::default()
         };
 
-        let result = execute_with_host(
+        let registry = SubscriptionRegistry::new();
+        registry
+            .subscribe(
+                "sub-matching",
+                "select * from state where schema_key = 'lix_key_value'",
+            )
+            .expect("subscribe should succeed");
+
+        let subscriber = RecordingSubscriber::default();
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'lix_key_value', 'f1', 'json', json('{}'), '1', json('{}'), 0)";
+        execute_with_host_and_subscriptions(
             &host,
+            &registry,
+            &subscriber,
             ExecuteRequest {
-                request_id: "req-write".to_owned(),
-                sql: "insert into file (id) values ('x')".to_owned(),
+                request_id: "req-sub".to_owned(),
+                sql: sql.to_owned(),
                 params: vec![],
-                plugin_change_requests: vec![PluginChangeRequest {
-                    plugin_key: "json".to_owned(),
-                    before: vec![1],
-                    after: vec![2],
-                }],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect("write execution should succeed");
+        .expect("execution with subscriptions should succeed");
+
+        let events = subscriber.events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "sub-matching");
+        match &events[0].1 {
+            SubscriptionEvent::Change { kind, entity_id, .. } => {
+                assert_eq!(*kind, ChangeKind::Insert);
+                assert_eq!(entity_id, "e1");
+            }
+            other => panic!("expected a Change event, got {other:?}"),
+        }
+    }
 
-        assert_eq!(result.statement_kind, RUST_KIND_WRITE_REWRITE);
-        assert_eq!(result.rows_affected, 2);
-        assert_eq!(result.last_insert_row_id, Some(10));
-        assert_eq!(
-            result.plugin_changes,
-            vec![json!({ "type": "file_update" })]
-        );
+    #[test]
+    fn subscription_does_not_fire_for_non_matching_schema_key() {
+        let host = TestHost {
+            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            }))),
+            ..Default::default()
+        };
+
+        let registry = SubscriptionRegistry::new();
+        registry
+            .subscribe(
+                "sub-other",
+                "select * from state where schema_key = 'lix_other_schema'",
+            )
+            .expect("subscribe should succeed");
+
+        let subscriber = RecordingSubscriber::default();
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e1', 'lix_key_value', 'f1', 'json', json('{}'), '1', json('{}'), 0)";
+        execute_with_host_and_subscriptions(
+            &host,
+            &registry,
+            &subscriber,
+            ExecuteRequest {
+                request_id: "req-sub-miss".to_owned(),
+                sql: sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("execution with subscriptions should succeed");
 
-        assert_eq!(host.execute_calls.borrow().len(), 1);
-        assert_eq!(host.detect_calls.borrow().len(), 1);
+        assert!(subscriber.events.borrow().is_empty());
     }
 
     #[test]
-    fn executes_validation_path_and_uses_sqlite_changes_policy() {
+    fn subscription_receives_change_event_for_matching_update() {
         let host = TestHost {
             execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
-                rows: vec![json!({ "ignored": true })],
-                rows_affected: 3,
+                rows: vec![],
+                rows_affected: 1,
                 last_insert_row_id: None,
             }))),
             ..Default::default()
         };
 
-        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'k', 'f', 'json', json('{}'), '1', json('{}'), 0)";
-        let result = execute_with_host(
+        let registry = SubscriptionRegistry::new();
+        registry
+            .subscribe(
+                "sub-update",
+                "select * from state where schema_key = 'lix_key_value'",
+            )
+            .expect("subscribe should succeed");
+
+        let subscriber = RecordingSubscriber::default();
+
+        let sql = "update state set snapshot_content = json('{\"value\":2}') where entity_id = 'e1' and schema_key = 'lix_key_value'";
+        execute_with_host_and_subscriptions(
             &host,
+            &registry,
+            &subscriber,
             ExecuteRequest {
-                request_id: "req-validation".to_owned(),
+                request_id: "req-sub-update".to_owned(),
                 sql: sql.to_owned(),
                 params: vec![],
-                plugin_change_requests: vec![PluginChangeRequest {
-                    plugin_key: "json".to_owned(),
-                    before: vec![],
-                    after: vec![],
-                }],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect("validation execution should succeed");
-
-        assert_eq!(result.statement_kind, RUST_KIND_VALIDATION);
-        assert_eq!(result.rows_affected, 3);
-        assert!(result.plugin_changes.is_empty());
-        assert!(host.detect_calls.borrow().is_empty());
+        .expect("execution with subscriptions should succeed");
+
+        let events = subscriber.events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "sub-update");
+        match &events[0].1 {
+            SubscriptionEvent::Change { kind, entity_id, .. } => {
+                assert_eq!(*kind, ChangeKind::Update);
+                assert_eq!(entity_id, "e1");
+            }
+            other => panic!("expected a Change event, got {other:?}"),
+        }
     }
 
     #[test]
-    fn executes_validation_detect_changes_for_lix_file_mutations() {
+    fn subscription_receives_change_event_for_matching_delete() {
         let host = TestHost {
             execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
                 rows: vec![],
                 rows_affected: 1,
                 last_insert_row_id: None,
             }))),
-            detect_response: RefCell::new(Some(Ok(HostDetectChangesResponse {
-                changes: vec![json!({ "type": "file_state_change" })],
-            }))),
             ..Default::default()
         };
 
-        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values (?, ?, ?, ?, json('{}'), ?, json('{}'), 0)";
-        let result = execute_with_host(
+        let registry = SubscriptionRegistry::new();
+        registry
+            .subscribe(
+                "sub-delete",
+                "select * from state where schema_key = 'lix_key_value'",
+            )
+            .expect("subscribe should succeed");
+
+        let subscriber = RecordingSubscriber::default();
+
+        let sql = "delete from state where entity_id = 'e1' and schema_key = 'lix_key_value'";
+        execute_with_host_and_subscriptions(
             &host,
+            &registry,
+            &subscriber,
             ExecuteRequest {
-                request_id: "req-validation-file".to_owned(),
+                request_id: "req-sub-delete".to_owned(),
                 sql: sql.to_owned(),
-                params: vec![
-                    json!("e"),
-                    json!("lix_file"),
-                    json!("f"),
-                    json!("json"),
-                    json!("1"),
-                ],
-                plugin_change_requests: vec![PluginChangeRequest {
-                    plugin_key: "json".to_owned(),
-                    before: vec![],
-                    after: vec![],
-                }],
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect("validation execution should succeed");
-
-        assert_eq!(
-            result.plugin_changes,
-            vec![json!({ "type": "file_state_change" })]
-        );
-        assert_eq!(host.detect_calls.borrow().len(), 1);
+        .expect("execution with subscriptions should succeed");
+
+        let events = subscriber.events.borrow();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, "sub-delete");
+        match &events[0].1 {
+            SubscriptionEvent::Change { kind, entity_id, .. } => {
+                assert_eq!(*kind, ChangeKind::Delete);
+                assert_eq!(entity_id, "e1");
+            }
+            other => panic!("expected a Change event, got {other:?}"),
+        }
     }
 
     #[test]
-    fn executes_passthrough_without_rewrite_or_detect_changes() {
+    fn subscribe_with_replay_emits_columns_rows_then_end_of_query() {
         let host = TestHost {
             execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
-                rows: vec![json!({ "user_version": 7 })],
-                rows_affected: 42,
+                rows: vec![json!({
+                    "entity_id": "e1",
+                    "schema_key": "lix_key_value",
+                    "file_id": "f1",
+                    "version_id": "global",
+                    "snapshot_content": { "value": 1 },
+                })],
+                rows_affected: 1,
                 last_insert_row_id: None,
             }))),
             ..Default::default()
         };
 
-        let result = execute_with_host(
+        let registry = SubscriptionRegistry::new();
+        let subscriber = RecordingSubscriber::default();
+
+        subscribe_with_replay(
+            &registry,
+            &host,
+            &subscriber,
+            "sub-replay",
+            "select * from state where schema_key = 'lix_key_value'",
+        )
+        .expect("subscribe with replay should succeed");
+
+        let events = subscriber.events.borrow();
+        assert_eq!(events.len(), 3);
+        assert!(matches!(events[0].1, SubscriptionEvent::Columns { .. }));
+        match &events[1].1 {
+            SubscriptionEvent::Row { entity_id, .. } => assert_eq!(entity_id, "e1"),
+            other => panic!("expected a Row event, got {other:?}"),
+        }
+        assert!(matches!(events[2].1, SubscriptionEvent::EndOfQuery));
+    }
+
+    use super::{execute_with_host_and_quotas, QuotaTracker, LIX_RUST_QUOTA_EXCEEDED};
+
+    #[test]
+    fn quota_tracker_rejects_insert_that_would_exceed_schema_key_limit() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: None,
+            execute_calls: RefCell::new(Vec::new()),
+        };
+        let quotas = QuotaTracker::default();
+        quotas
+            .set_schema_key_limit(&host, "mock_schema", 1)
+            .expect("seeding the schema_key limit should succeed");
+
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host_and_quotas(
+            &quotas,
             &host,
             ExecuteRequest {
-                request_id: "req-pass".to_owned(),
-                sql: "pragma user_version".to_owned(),
+                request_id: "req-quota-1".to_owned(),
+                sql: sql.to_owned(),
                 params: vec![],
-                plugin_change_requests: vec![PluginChangeRequest {
-                    plugin_key: "json".to_owned(),
-                    before: vec![],
-                    after: vec![],
-                }],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect("passthrough execution should succeed");
+        .expect("first insert should stay within the quota");
 
-        assert_eq!(result.statement_kind, RUST_KIND_PASSTHROUGH);
-        assert_eq!(result.rows_affected, 1);
-        assert!(result.plugin_changes.is_empty());
-        assert_eq!(host.execute_calls.borrow().len(), 1);
-        assert!(host.detect_calls.borrow().is_empty());
+        let sql_second = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e2', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host_and_quotas(
+            &quotas,
+            &host,
+            ExecuteRequest {
+                request_id: "req-quota-2".to_owned(),
+                sql: sql_second.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect_err("second insert should exceed the schema_key quota");
+
+        assert_eq!(error.code, LIX_RUST_QUOTA_EXCEEDED);
     }
 
     #[test]
-    fn maps_execute_failures_to_stable_sqlite_error_code() {
-        let host = TestHost {
-            execute_response: RefCell::new(Some(Err(EngineError::new(
-                "UNCLASSIFIED",
-                "SQLITE_ERROR: no such table: missing",
-            )))),
-            ..Default::default()
+    fn quota_tracker_decrements_schema_key_count_on_delete() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: Some(json!({ "count": 1 })),
+            execute_calls: RefCell::new(Vec::new()),
         };
+        let quotas = QuotaTracker::default();
+        quotas
+            .set_schema_key_limit(&host, "mock_schema", 1)
+            .expect("seeding the schema_key limit should succeed");
+
+        let insert_sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host_and_quotas(
+            &quotas,
+            &host,
+            ExecuteRequest {
+                request_id: "req-quota-3".to_owned(),
+                sql: insert_sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("insert should stay within the quota");
 
-        let error = execute_with_host(
+        let delete_sql = "delete from state where entity_id = 'e1' and schema_key = 'mock_schema'";
+        execute_with_host_and_quotas(
+            &quotas,
             &host,
             ExecuteRequest {
-                request_id: "req-error".to_owned(),
-                sql: "select * from missing".to_owned(),
+                request_id: "req-quota-4".to_owned(),
+                sql: delete_sql.to_owned(),
                 params: vec![],
                 plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect_err("execution should fail");
+        .expect("delete should free up the quota");
 
-        assert_eq!(error.code, LIX_RUST_SQLITE_EXECUTION);
+        let reinsert_sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e2', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host_and_quotas(
+            &quotas,
+            &host,
+            ExecuteRequest {
+                request_id: "req-quota-5".to_owned(),
+                sql: reinsert_sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("insert after the delete should fit back within the quota");
     }
 
     #[test]
-    fn maps_detect_changes_failures_to_stable_error_code() {
-        let host = TestHost {
-            execute_response: RefCell::new(Some(Ok(HostExecuteResponse {
-                rows: vec![],
-                rows_affected: 1,
-                last_insert_row_id: None,
-            }))),
-            detect_response: RefCell::new(Some(Err(EngineError::new(
-                "UNCLASSIFIED",
-                "plugin failed",
-            )))),
-            ..Default::default()
+    fn quota_tracker_does_not_count_a_no_op_do_nothing_upsert_as_a_new_row() {
+        let host = UpsertHost {
+            schema_value: upsert_schema(),
+            existing_snapshot: Some(json!({ "count": 1 })),
+            execute_calls: RefCell::new(Vec::new()),
         };
+        let quotas = QuotaTracker::default();
+        quotas
+            .set_schema_key_limit(&host, "mock_schema", 1)
+            .expect("seeding the schema_key limit should succeed");
+
+        let insert_sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        execute_with_host_and_quotas(
+            &quotas,
+            &host,
+            ExecuteRequest {
+                request_id: "req-quota-do-nothing-1".to_owned(),
+                sql: insert_sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("first insert should reach the schema_key limit");
+
+        // `e1` now conflicts with a row the host reports as existing (per
+        // `existing_snapshot`), so this `DO NOTHING` upsert is a no-op that
+        // creates no row. Even though the quota is already at its cap, it
+        // must not be rejected for a write that adds nothing.
+        let do_nothing_sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0) \
+                    on conflict(entity_id, schema_key, file_id, version_id) do nothing";
+        execute_with_host_and_quotas(
+            &quotas,
+            &host,
+            ExecuteRequest {
+                request_id: "req-quota-do-nothing-2".to_owned(),
+                sql: do_nothing_sql.to_owned(),
+                params: vec![],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
+            },
+        )
+        .expect("a no-op DO NOTHING upsert should not be blocked by the quota");
 
-        let error = execute_with_host(
+        let new_row_sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e2', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host_and_quotas(
+            &quotas,
             &host,
             ExecuteRequest {
-                request_id: "req-detect-error".to_owned(),
-                sql: "insert into file (id) values ('x')".to_owned(),
+                request_id: "req-quota-do-nothing-3".to_owned(),
+                sql: new_row_sql.to_owned(),
                 params: vec![],
-                plugin_change_requests: vec![PluginChangeRequest {
-                    plugin_key: "json".to_owned(),
-                    before: vec![],
-                    after: vec![],
-                }],
+                plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect_err("detect changes should fail");
+        .expect_err("a genuinely new row should still be rejected once the limit is reached");
 
-        assert_eq!(error.code, LIX_RUST_DETECT_CHANGES);
+        assert_eq!(error.code, LIX_RUST_QUOTA_EXCEEDED);
     }
 
-    #[test]
-    fn returns_validation_error_for_non_state_validation_mutation() {
-        let host = TestHost::default();
+    struct QuotaSeedHost {
+        schema_value: Value,
+        existing_row_count: i64,
+    }
 
-        let error = execute_with_host(
+    impl HostCallbacks for QuotaSeedHost {
+        fn execute(&self, request: HostExecuteRequest) -> Result<HostExecuteResponse, EngineError> {
+            let lowered = request.sql.to_lowercase();
+            if lowered.contains("from stored_schema") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "value": self.schema_value.clone() })],
+                    rows_affected: 1,
+                    last_insert_row_id: None,
+                });
+            }
+            if lowered.contains("count(*)") {
+                return Ok(HostExecuteResponse {
+                    rows: vec![json!({ "count": self.existing_row_count })],
+                    rows_affected: 0,
+                    last_insert_row_id: None,
+                });
+            }
+            Ok(HostExecuteResponse {
+                rows: vec![],
+                rows_affected: 1,
+                last_insert_row_id: None,
+            })
+        }
+
+        fn detect_changes(
+            &self,
+            _request: HostDetectChangesRequest,
+        ) -> Result<HostDetectChangesResponse, EngineError> {
+            Ok(HostDetectChangesResponse {
+                changes: Vec::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn quota_tracker_seeds_schema_key_count_from_rows_the_host_already_has() {
+        let host = QuotaSeedHost {
+            schema_value: upsert_schema(),
+            existing_row_count: 1,
+        };
+        let quotas = QuotaTracker::default();
+        quotas
+            .set_schema_key_limit(&host, "mock_schema", 1)
+            .expect("seeding the schema_key limit should succeed");
+
+        // The schema already has one row (per `existing_row_count`) before
+        // this `QuotaTracker` was ever attached, so a limit of 1 must reject
+        // the very first insert this fresh process sees, instead of starting
+        // back at an unseeded count of zero.
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let error = execute_with_host_and_quotas(
+            &quotas,
             &host,
             ExecuteRequest {
-                request_id: "req-invalid-validation".to_owned(),
-                sql: "update stateful set schema_key = 'x' where entity_id = 'e'".to_owned(),
+                request_id: "req-quota-seed".to_owned(),
+                sql: sql.to_owned(),
                 params: vec![],
                 plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect_err("invalid validation target should fail");
+        .expect_err("schema is already at the limit once existing rows are counted");
 
-        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+        assert_eq!(error.code, LIX_RUST_QUOTA_EXCEEDED);
     }
 
     #[test]
-    fn returns_validation_error_for_snapshot_schema_violation() {
-        let schema = json!({
-            "type": "object",
-            "x-lix-key": "mock_schema",
-            "x-lix-version": "1.0",
-            "properties": {
-                "name": { "type": "string" }
-            },
-            "required": ["name"],
-            "additionalProperties": false
-        });
-        let host = ValidationHost {
-            execute_calls: RefCell::new(Vec::new()),
-            schema_value: schema,
-        };
+    fn dry_run_insert_skips_host_execute_but_reports_projected_effect() {
+        let host = TestHost::default();
 
-        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
-        let error = execute_with_host(
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let result = execute_with_host(
             &host,
             ExecuteRequest {
-                request_id: "req-schema-invalid".to_owned(),
+                request_id: "req-dry-run-insert".to_owned(),
                 sql: sql.to_owned(),
                 params: vec![],
                 plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: true,
             },
         )
-        .expect_err("invalid snapshot should fail validation");
+        .expect("dry run insert should succeed without mutating state");
 
-        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+        assert!(result.rows.is_empty());
+        assert_eq!(result.rows_affected, 1);
+        assert!(result.last_insert_row_id.is_none());
+        assert_eq!(result.transaction_report.asserted.len(), 1);
+        let rewritten_sql = result
+            .rewritten_sql
+            .as_deref()
+            .expect("dry run should report the rewritten SQL");
+        assert!(rewritten_sql.contains(super::MUTATION_ROW_CTE));
+
+        let calls = host.execute_calls.borrow();
+        assert!(
+            !calls
+                .iter()
+                .any(|call| call.sql.to_lowercase().contains("insert into")),
+            "dry run should never send the mutating insert to host.execute"
+        );
     }
 
     #[test]
-    fn returns_validation_error_for_invalid_cel_in_schema() {
-        let schema = json!({
-            "type": "object",
-            "x-lix-key": "mock_schema",
-            "x-lix-version": "1.0",
-            "properties": {
-                "name": {
-                    "type": "string",
-                    "x-lix-default": "1 +"
-                }
-            },
-            "additionalProperties": false
-        });
-        let host = ValidationHost {
-            execute_calls: RefCell::new(Vec::new()),
-            schema_value: schema,
-        };
+    fn non_dry_run_insert_still_mutates_and_omits_rewritten_sql() {
+        let host = TestHost::default();
 
-        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) values ('e', 'mock_schema', 'f', 'json', json('{\"name\":\"ok\"}'), '1.0', json('{}'), 0)";
-        let error = execute_with_host(
+        let sql = "insert into state (entity_id, schema_key, file_id, plugin_key, snapshot_content, schema_version, metadata, untracked) \
+                    values ('e1', 'mock_schema', 'f', 'json', json('{\"count\":1}'), '1.0', json('{}'), 0)";
+        let result = execute_with_host(
             &host,
             ExecuteRequest {
-                request_id: "req-cel-invalid".to_owned(),
+                request_id: "req-real-insert".to_owned(),
                 sql: sql.to_owned(),
                 params: vec![],
                 plugin_change_requests: vec![],
+                as_of: None,
+                prepared_name: None,
+                dry_run: false,
             },
         )
-        .expect_err("invalid CEL expression should fail validation");
+        .expect("insert should succeed");
 
-        assert_eq!(error.code, LIX_RUST_REWRITE_VALIDATION);
+        assert!(result.rewritten_sql.is_none());
+        let calls = host.execute_calls.borrow();
+        assert!(calls
+            .iter()
+            .any(|call| call.sql.to_lowercase().contains("insert into")));
     }
 }
@@ -1,62 +1,450 @@
+mod batch;
+mod cache;
+mod format;
+mod http;
+
 use std::env;
+use std::io::{self, Read, Write};
+use std::path::Path;
 use std::process;
 
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
 use lix_engine::{
     plan_execute, rewrite_sql_for_execution, route_statement_kind, RUST_KIND_PASSTHROUGH,
 };
 
+/// A command's outcome before it is rendered: `plain_text` is what legacy
+/// (non-`--json`) invocations print to stdout, `data` is the same result as a
+/// JSON value for the `{ "ok": true, "data": ... }` envelope.
+struct CliSuccess {
+    plain_text: String,
+    data: Value,
+}
+
+impl CliSuccess {
+    fn new(plain_text: impl Into<String>, data: Value) -> Self {
+        Self {
+            plain_text: plain_text.into(),
+            data,
+        }
+    }
+}
+
 fn main() {
-    let args: Vec<String> = env::args().collect();
+    let mut args: Vec<String> = env::args().collect();
+    let no_cache = strip_flag(&mut args, "--no-cache");
+    let json_mode = strip_flag(&mut args, "--json");
 
     if args.len() < 2 {
-        eprintln!("expected command: route");
-        process::exit(2);
+        usage_error(json_mode, "expected command: route");
     }
 
-    match args[1].as_str() {
+    let outcome: Result<CliSuccess, lix_engine::EngineError> = match args[1].as_str() {
         "route" => {
             if args.len() < 3 {
-                println!("{}", RUST_KIND_PASSTHROUGH);
-                return;
+                Ok(CliSuccess::new(
+                    RUST_KIND_PASSTHROUGH,
+                    Value::String(RUST_KIND_PASSTHROUGH.to_owned()),
+                ))
+            } else {
+                let sql = args[2..].join(" ");
+                let kind = cached_route(&sql, no_cache);
+                Ok(CliSuccess::new(kind.clone(), Value::String(kind)))
             }
-
-            let sql = args[2..].join(" ");
-            let kind = route_statement_kind(&sql);
-            println!("{}", kind);
         }
         "plan" => {
             if args.len() < 3 {
-                eprintln!("expected SQL argument for plan command");
-                process::exit(2);
+                usage_error(json_mode, "expected SQL argument for plan command");
             }
             let sql = args[2..].join(" ");
-            let plan = plan_execute(&sql);
-            match serde_json::to_string(&plan) {
-                Ok(json) => println!("{}", json),
-                Err(error) => {
-                    eprintln!("failed to serialize execute plan: {}", error);
-                    process::exit(1);
-                }
-            }
+            let plan = cached_plan(&sql, no_cache);
+            let data = serde_json::to_value(&plan).expect("ExecutePlan always serializes");
+            Ok(CliSuccess::new(data.to_string(), data))
         }
         "rewrite" => {
             if args.len() < 4 {
-                eprintln!("expected statement kind and SQL arguments for rewrite command");
-                process::exit(2);
+                usage_error(
+                    json_mode,
+                    "expected statement kind and SQL arguments for rewrite command",
+                );
             }
             let statement_kind = args[2].as_str();
             let sql = args[3..].join(" ");
-            match rewrite_sql_for_execution(&sql, statement_kind) {
-                Ok(rewritten) => println!("{rewritten}"),
-                Err(error) => {
-                    eprintln!("{}: {}", error.code, error.message);
-                    process::exit(1);
+            rewrite_sql_for_execution(&sql, statement_kind)
+                .map(|rewritten| CliSuccess::new(rewritten.clone(), Value::String(rewritten)))
+        }
+        "serve" => {
+            run_serve();
+            return;
+        }
+        "prepare" => {
+            let path = args.get(2).map(Path::new);
+            Ok(run_prepare(path, no_cache, json_mode))
+        }
+        "batch" => {
+            let mut rest: Vec<String> = args[2..].to_vec();
+            let with_plan = strip_flag(&mut rest, "--with-plan");
+            let path = rest.first().map(Path::new);
+            let source = cache::read_source(path).unwrap_or_else(|error| {
+                runtime_error(json_mode, &format!("batch: failed to read source: {error}"))
+            });
+            let statements = batch::route_batch(&source, with_plan);
+            let data = batch::batch_to_json(&statements);
+            Ok(CliSuccess::new(data.to_string(), data))
+        }
+        "format" => {
+            if args.len() < 3 {
+                usage_error(json_mode, "expected SQL argument for format command");
+            }
+            let sql = args[2..].join(" ");
+            format::format_sql(&sql)
+                .map(|formatted| CliSuccess::new(formatted.clone(), Value::String(formatted)))
+        }
+        "serve-http" => {
+            let addr = parse_addr_flag(&args[2..])
+                .unwrap_or_else(|| usage_error(json_mode, "expected --addr <host:port> for serve-http command"));
+            if let Err(error) = http::run_serve_http(&addr) {
+                runtime_error::<()>(json_mode, &format!("serve-http: failed to run HTTP server: {error}"));
+            }
+            return;
+        }
+        other => usage_error(json_mode, &format!("unsupported command: {other}")),
+    };
+
+    finish(json_mode, outcome);
+}
+
+/// Renders a command's `Result` as either its legacy plain-text output or the
+/// `{ "ok", "data"|"error" }` envelope, then exits with the documented code:
+/// 0 on success, 1 on an engine error (carrying `error.code`).
+fn finish(json_mode: bool, outcome: Result<CliSuccess, lix_engine::EngineError>) {
+    match outcome {
+        Ok(success) => {
+            if json_mode {
+                let envelope = serde_json::json!({ "ok": true, "data": success.data });
+                println!("{envelope}");
+            } else {
+                println!("{}", success.plain_text);
+            }
+            process::exit(0);
+        }
+        Err(error) => {
+            if json_mode {
+                let envelope = serde_json::json!({
+                    "ok": false,
+                    "error": { "code": error.code, "message": error.message },
+                });
+                println!("{envelope}");
+            } else {
+                eprintln!("{}: {}", error.code, error.message);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+/// Reports a usage error (missing/invalid arguments) and exits with code 2,
+/// in the `--json` envelope when requested.
+fn usage_error<T>(json_mode: bool, message: &str) -> T {
+    if json_mode {
+        let envelope = serde_json::json!({
+            "ok": false,
+            "error": { "code": "LIX_RUST_USAGE", "message": message },
+        });
+        println!("{envelope}");
+    } else {
+        eprintln!("{message}");
+    }
+    process::exit(2);
+}
+
+/// Reports a non-engine runtime failure (e.g. an I/O error reading a batch
+/// source) and exits with code 1.
+fn runtime_error<T>(json_mode: bool, message: &str) -> T {
+    if json_mode {
+        let envelope = serde_json::json!({
+            "ok": false,
+            "error": { "code": "LIX_RUST_UNKNOWN", "message": message },
+        });
+        println!("{envelope}");
+    } else {
+        eprintln!("{message}");
+    }
+    process::exit(1);
+}
+
+/// Removes `flag` from `args` wherever it appears and reports whether it was
+/// present, so commands can accept `--no-cache` in any position.
+fn strip_flag(args: &mut Vec<String>, flag: &str) -> bool {
+    let mut found = false;
+    args.retain(|arg| {
+        if arg == flag {
+            found = true;
+            false
+        } else {
+            true
+        }
+    });
+    found
+}
+
+fn parse_addr_flag(args: &[String]) -> Option<String> {
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--addr" {
+            return iter.next().cloned();
+        }
+    }
+    None
+}
+
+fn cached_route(sql: &str, no_cache: bool) -> String {
+    cached_plan(sql, no_cache).statement_kind.to_owned()
+}
+
+fn cached_plan(sql: &str, no_cache: bool) -> lix_engine::ExecutePlan {
+    if no_cache {
+        return plan_execute(sql);
+    }
+
+    let normalized = cache::normalize_sql(sql);
+    let hash = cache::cache_key(&normalized, "plan");
+
+    if let Some(entry) = cache::load(&hash) {
+        return entry.plan;
+    }
+
+    let plan = plan_execute(sql);
+    let entry = cache::CacheEntry {
+        routed_kind: plan.statement_kind.to_owned(),
+        plan: plan.clone(),
+    };
+    if let Err(error) = cache::store(&hash, &entry) {
+        eprintln!("warning: failed to write plan cache entry: {error}");
+    }
+    plan
+}
+
+/// Pre-populates `.lix/` with the routed kind and execute plan for every
+/// statement found in `path` (or stdin when absent), so later `plan`/`route`
+/// invocations hit the cache instead of re-parsing.
+fn run_prepare(path: Option<&Path>, no_cache: bool, json_mode: bool) -> CliSuccess {
+    let source = cache::read_source(path).unwrap_or_else(|error| {
+        runtime_error(json_mode, &format!("prepare: failed to read source: {error}"))
+    });
+
+    let statements = cache::prepare_statements(&source);
+    for statement in &statements {
+        cached_plan(statement, no_cache);
+    }
+    let count = statements.len();
+    CliSuccess::new(
+        format!("prepared {count} statement(s)"),
+        serde_json::json!({ "preparedCount": count }),
+    )
+}
+
+#[derive(Debug, Deserialize)]
+struct ServeRequest {
+    id: i64,
+    method: String,
+    #[serde(default)]
+    params: Value,
+}
+
+#[derive(Debug, Serialize)]
+struct ServeResponse {
+    id: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ServeError>,
+}
+
+#[derive(Debug, Serialize)]
+struct ServeError {
+    code: &'static str,
+    message: String,
+}
+
+/// Runs the LSP-style `Content-Length` framed JSON-RPC loop over stdin/stdout,
+/// so a supervising process can pipeline route/plan/rewrite calls without
+/// spawning a process per statement.
+fn run_serve() {
+    let stdin = io::stdin();
+    let mut reader = stdin.lock();
+    let stdout = io::stdout();
+    let mut writer = stdout.lock();
+
+    loop {
+        let body = match read_framed_message(&mut reader) {
+            Ok(Some(body)) => body,
+            Ok(None) => break,
+            Err(message) => {
+                eprintln!("serve: failed to read framed message: {message}");
+                break;
+            }
+        };
+
+        let response = match serde_json::from_slice::<ServeRequest>(&body) {
+            Ok(request) => handle_serve_request(request),
+            Err(error) => ServeResponse {
+                id: 0,
+                result: None,
+                error: Some(ServeError {
+                    code: "LIX_RUST_PROTOCOL_MISMATCH",
+                    message: format!("failed to parse request: {error}"),
+                }),
+            },
+        };
+
+        if let Err(error) = write_framed_message(&mut writer, &response) {
+            eprintln!("serve: failed to write framed response: {error}");
+            break;
+        }
+    }
+}
+
+fn handle_serve_request(request: ServeRequest) -> ServeResponse {
+    let id = request.id;
+    match request.method.as_str() {
+        "route" => {
+            let sql = match request.params.get("sql").and_then(Value::as_str) {
+                Some(sql) => sql,
+                None => return serve_protocol_error(id, "route requires a \"sql\" param"),
+            };
+            let kind = route_statement_kind(sql);
+            ServeResponse {
+                id,
+                result: Some(Value::String(kind.to_owned())),
+                error: None,
+            }
+        }
+        "plan" => {
+            let sql = match request.params.get("sql").and_then(Value::as_str) {
+                Some(sql) => sql,
+                None => return serve_protocol_error(id, "plan requires a \"sql\" param"),
+            };
+            let plan = plan_execute(sql);
+            match serde_json::to_value(plan) {
+                Ok(result) => ServeResponse {
+                    id,
+                    result: Some(result),
+                    error: None,
+                },
+                Err(error) => serve_protocol_error(id, &format!("failed to serialize plan: {error}")),
+            }
+        }
+        "rewrite" => {
+            let sql = match request.params.get("sql").and_then(Value::as_str) {
+                Some(sql) => sql,
+                None => return serve_protocol_error(id, "rewrite requires a \"sql\" param"),
+            };
+            let statement_kind = match request.params.get("statementKind").and_then(Value::as_str)
+            {
+                Some(kind) => kind,
+                None => {
+                    return serve_protocol_error(id, "rewrite requires a \"statementKind\" param")
                 }
+            };
+            match rewrite_sql_for_execution(sql, statement_kind) {
+                Ok(rewritten) => ServeResponse {
+                    id,
+                    result: Some(Value::String(rewritten)),
+                    error: None,
+                },
+                Err(error) => ServeResponse {
+                    id,
+                    result: None,
+                    error: Some(ServeError {
+                        code: error.code,
+                        message: error.message,
+                    }),
+                },
             }
         }
-        _ => {
-            eprintln!("unsupported command: {}", args[1]);
-            process::exit(2);
+        other => serve_protocol_error(id, &format!("unsupported method: {other}")),
+    }
+}
+
+fn serve_protocol_error(id: i64, message: &str) -> ServeResponse {
+    ServeResponse {
+        id,
+        result: None,
+        error: Some(ServeError {
+            code: "LIX_RUST_PROTOCOL_MISMATCH",
+            message: message.to_owned(),
+        }),
+    }
+}
+
+fn read_framed_message(reader: &mut impl Read) -> Result<Option<Vec<u8>>, String> {
+    let mut content_length: Option<usize> = None;
+    let mut header_line = Vec::new();
+
+    loop {
+        header_line.clear();
+        match read_header_line(reader, &mut header_line) {
+            Ok(true) => {}
+            Ok(false) => return Ok(None),
+            Err(error) => return Err(error),
+        }
+
+        if header_line.is_empty() {
+            break;
+        }
+
+        let line = String::from_utf8_lossy(&header_line);
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            content_length = value
+                .trim()
+                .parse::<usize>()
+                .map(Some)
+                .map_err(|error| format!("invalid Content-Length header: {error}"))?;
         }
     }
+
+    let content_length =
+        content_length.ok_or_else(|| "missing Content-Length header".to_owned())?;
+
+    let mut body = vec![0u8; content_length];
+    reader
+        .read_exact(&mut body)
+        .map_err(|error| format!("failed to read message body: {error}"))?;
+
+    Ok(Some(body))
+}
+
+/// Reads a single `\r\n`-terminated header line. Returns `Ok(false)` on EOF
+/// before any bytes were read, mirroring the behaviour callers need to
+/// distinguish "stream closed" from "empty line".
+fn read_header_line(reader: &mut impl Read, out: &mut Vec<u8>) -> Result<bool, String> {
+    let mut byte = [0u8; 1];
+    let mut saw_any = false;
+
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => return Ok(saw_any),
+            Ok(_) => {
+                saw_any = true;
+                if byte[0] == b'\n' {
+                    if out.last() == Some(&b'\r') {
+                        out.pop();
+                    }
+                    return Ok(true);
+                }
+                out.push(byte[0]);
+            }
+            Err(error) => return Err(format!("failed to read header byte: {error}")),
+        }
+    }
+}
+
+fn write_framed_message(writer: &mut impl Write, response: &ServeResponse) -> io::Result<()> {
+    let body = serde_json::to_vec(response).expect("ServeResponse always serializes");
+    write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+    writer.write_all(&body)?;
+    writer.flush()
 }